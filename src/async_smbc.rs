@@ -0,0 +1,532 @@
+//! `async_smbc` runs an Smbc on a worker thread of its own, for async code
+//! (the async feature)
+
+use std::{
+    collections::VecDeque,
+    future::{self, Future},
+    io::{Error, Read, Result as IoResult, Seek, SeekFrom, Write},
+    mem,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    pin::Pin,
+    sync::mpsc,
+    task::{ready, Context, Poll},
+    thread,
+};
+
+use crate::{
+    error::{SmbcError, SmbcResult},
+    metadata::{SmbcFsStats, SmbcMetadata},
+    smbc::{
+        Mode, OFlag, Smbc, SmbcDirEntry, SmbcDirectory, SmbcFile, SmbcXAttr, SmbcXAttrValue,
+        XAttrFlags,
+    },
+};
+use futures_core::Stream;
+use tokio::{
+    io::{AsyncRead, AsyncSeek, AsyncWrite, ReadBuf},
+    sync::oneshot,
+};
+
+#[test]
+fn test_fill() {
+    let data = b"quarterly report";
+    let mut pos = 0;
+    let mut out = [0u8; 9];
+    let mut dst = ReadBuf::new(&mut out);
+    fill(data, &mut pos, &mut dst);
+    assert_eq!(dst.filled(), b"quarterly");
+    assert_eq!(pos, 9);
+
+    let mut out = [0u8; 32];
+    let mut dst = ReadBuf::new(&mut out);
+    fill(data, &mut pos, &mut dst);
+    assert_eq!(dst.filled(), b" report");
+    assert_eq!(pos, data.len());
+    fill(data, &mut pos, &mut dst);
+    assert_eq!(dst.filled(), b" report");
+}
+
+/// The most an AsyncSmbcFile reads or writes in one call on the worker
+const MAX_IO: usize = 1 << 20;
+/// The entries an AsyncSmbcDirectory reads in one call on the worker
+const DIR_BATCH: usize = 64;
+
+/// A call for the worker thread to run
+type Job = Box<dyn FnOnce(&Smbc) + Send>;
+
+#[derive(Clone)]
+/// An Smbc for async code.
+///
+/// libsmbclient contexts are not thread-safe, so the Smbc is moved to a
+/// worker thread of its own and every call on it (and on the files and
+/// directories opened through it) runs there, one at a time and in the
+/// order they were made.  Nothing blocks the async runtime.  For calls in
+/// parallel use one AsyncSmbc (one context) per task.  The worker exits
+/// once the AsyncSmbc, its clones and everything opened through it are
+/// dropped.
+///
+/// ```no_run
+/// use rust_smb::{AsyncSmbc, OFlag, Mode, Smbc};
+/// use std::path::Path;
+/// use tokio::io::AsyncReadExt;
+///
+/// # async fn run() -> rust_smb::SmbcResult<()> {
+/// let smbc = AsyncSmbc::new(Smbc::new_with_auth(0)?)?;
+/// let path = Path::new("smb://files01/reports/2019 q1.csv");
+/// let mut file = smbc.open(path, OFlag::O_RDONLY, Mode::empty()).await?;
+/// let mut report = String::new();
+/// file.read_to_string(&mut report).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncSmbc {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl AsyncSmbc {
+    /// Move smbc to a new worker thread
+    pub fn new(smbc: Smbc) -> SmbcResult<Self> {
+        let (jobs, queue) = mpsc::channel::<Job>();
+        thread::Builder::new().name("smbc".to_string()).spawn(move || {
+            for job in queue {
+                // a panicking call drops its reply, the worker carries on
+                let _ = panic::catch_unwind(AssertUnwindSafe(|| job(&smbc)));
+            }
+        })?;
+        Ok(AsyncSmbc { jobs })
+    }
+
+    /// Run f on the worker thread, with the Smbc.  For the calls that have
+    /// no async version.
+    ///
+    /// @param op        the name of the call, for the error when the
+    ///                  worker drops it (f panicked)
+    pub async fn run<T, F>(&self, op: &'static str, f: F) -> SmbcResult<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Smbc) -> SmbcResult<T> + Send + 'static,
+    {
+        self.spawn(f).await.map_err(|_| SmbcError::IoError(dropped(op)))?
+    }
+
+    /// Queue f on the worker.  The receiver fails when the worker drops
+    /// it.
+    fn spawn<T, F>(&self, f: F) -> oneshot::Receiver<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Smbc) -> T + Send + 'static,
+    {
+        let (reply, receiver) = oneshot::channel();
+        // a worker that is gone drops the job, and with it the reply
+        let _ = self.jobs.send(Box::new(move |smbc| {
+            let _ = reply.send(f(smbc));
+        }));
+        receiver
+    }
+
+    /// See Smbc::stat
+    pub async fn stat(&self, path: &Path) -> SmbcResult<SmbcMetadata> {
+        let path = path.to_path_buf();
+        self.run("stat", move |smbc| smbc.stat(&path)).await
+    }
+
+    /// See Smbc::statvfs
+    pub async fn statvfs(&self, path: &Path) -> SmbcResult<SmbcFsStats> {
+        let path = path.to_path_buf();
+        self.run("statvfs", move |smbc| smbc.statvfs(&path)).await
+    }
+
+    /// See Smbc::open
+    pub async fn open(&self, path: &Path, flags: OFlag, mode: Mode) -> SmbcResult<AsyncSmbcFile> {
+        let path = path.to_path_buf();
+        let file = self.run("open", move |smbc| smbc.open(&path, flags, mode)).await?;
+        Ok(AsyncSmbcFile::new(self.clone(), file))
+    }
+
+    /// See Smbc::create
+    pub async fn create(&self, path: &Path, mode: Mode) -> SmbcResult<AsyncSmbcFile> {
+        let path = path.to_path_buf();
+        let file = self.run("create", move |smbc| smbc.create(&path, mode)).await?;
+        Ok(AsyncSmbcFile::new(self.clone(), file))
+    }
+
+    /// See Smbc::opendir.  The directory is a Stream of its entries.
+    pub async fn opendir(&self, path: &Path) -> SmbcResult<AsyncSmbcDirectory> {
+        let path = path.to_path_buf();
+        let dir = self.run("opendir", move |smbc| smbc.opendir(&path)).await?;
+        Ok(AsyncSmbcDirectory::new(self.clone(), dir))
+    }
+
+    /// See Smbc::mkdir
+    pub async fn mkdir(&self, path: &Path, mode: Mode) -> SmbcResult<()> {
+        let path = path.to_path_buf();
+        self.run("mkdir", move |smbc| smbc.mkdir(&path, mode)).await
+    }
+
+    /// See Smbc::rmdir
+    pub async fn rmdir(&self, path: &Path) -> SmbcResult<()> {
+        let path = path.to_path_buf();
+        self.run("rmdir", move |smbc| smbc.rmdir(&path)).await
+    }
+
+    /// See Smbc::unlink
+    pub async fn unlink(&self, path: &Path) -> SmbcResult<()> {
+        let path = path.to_path_buf();
+        self.run("unlink", move |smbc| smbc.unlink(&path)).await
+    }
+
+    /// See Smbc::rename
+    pub async fn rename(&self, oldpath: &Path, newpath: &Path) -> SmbcResult<()> {
+        let (oldpath, newpath) = (oldpath.to_path_buf(), newpath.to_path_buf());
+        self.run("rename", move |smbc| smbc.rename(&oldpath, &newpath)).await
+    }
+
+    /// See Smbc::getxattr
+    pub async fn getxattr(&self, path: &Path, attr: &SmbcXAttr) -> SmbcResult<Vec<u8>> {
+        let (path, attr) = (path.to_path_buf(), attr.clone());
+        self.run("getxattr", move |smbc| smbc.getxattr(&path, &attr)).await
+    }
+
+    /// See Smbc::listxattr
+    pub async fn listxattr(&self, path: &Path) -> SmbcResult<Vec<u8>> {
+        let path = path.to_path_buf();
+        self.run("listxattr", move |smbc| smbc.listxattr(&path)).await
+    }
+
+    /// See Smbc::setxattr
+    pub async fn setxattr(
+        &self,
+        path: &Path,
+        attr: &SmbcXAttr,
+        value: &SmbcXAttrValue,
+        flags: XAttrFlags,
+    ) -> SmbcResult<()> {
+        let (path, attr, value) = (path.to_path_buf(), attr.clone(), value.clone());
+        self.run("setxattr", move |smbc| smbc.setxattr(&path, &attr, &value, flags)).await
+    }
+
+    /// See Smbc::removexattr
+    pub async fn removexattr(&self, path: &Path, attr: &SmbcXAttr) -> SmbcResult<()> {
+        let (path, attr) = (path.to_path_buf(), attr.clone());
+        self.run("removexattr", move |smbc| smbc.removexattr(&path, &attr)).await
+    }
+}
+
+/// The error of a call the worker dropped
+fn dropped(op: &str) -> Error {
+    Error::other(format!("{}: the smbc worker thread dropped the call", op))
+}
+
+/// Copy what is left of buf after pos into dst
+fn fill(buf: &[u8], pos: &mut usize, dst: &mut ReadBuf<'_>) {
+    let n = dst.remaining().min(buf.len() - *pos);
+    dst.put_slice(&buf[*pos..*pos + n]);
+    *pos += n;
+}
+
+/// What a call on the worker did to an AsyncSmbcFile
+enum FileOp {
+    /// the data read
+    Read(Vec<u8>),
+    /// the data queued was written
+    Write,
+    /// the new offset
+    Seek(u64),
+}
+
+/// An SmbcFile for async code: AsyncRead, AsyncWrite and AsyncSeek, each
+/// call running on the worker of the AsyncSmbc that opened it.
+///
+/// One read, write or seek runs at a time.  As with tokio::fs::File, a
+/// write returns as soon as its data is queued on the worker: an error
+/// writing it is returned by the next call on the file, and flush waits
+/// for it.  Reads may read ahead of the data returned, the read ahead
+/// data is given back before the next write or seek.
+pub struct AsyncSmbcFile {
+    smbc: AsyncSmbc,
+    /// the file, None once dropped
+    file: Option<SmbcFile>,
+    /// the call running on the worker, with the file offset after it
+    busy: Option<oneshot::Receiver<(IoResult<FileOp>, Option<u64>)>>,
+    /// the outcome of the last call, until a poll takes it
+    done: Option<IoResult<FileOp>>,
+    /// data read but not yet returned, from buf_pos
+    buf: Vec<u8>,
+    buf_pos: usize,
+    /// the file offset, None when unknown
+    pos: Option<u64>,
+}
+
+impl AsyncSmbcFile {
+    fn new(smbc: AsyncSmbc, file: SmbcFile) -> Self {
+        AsyncSmbcFile {
+            smbc,
+            file: Some(file),
+            busy: None,
+            done: None,
+            buf: vec![],
+            buf_pos: 0,
+            pos: Some(0),
+        }
+    }
+
+    /// See Smbc::fstat
+    pub async fn metadata(&self) -> SmbcResult<SmbcMetadata> {
+        let file = self.file().clone();
+        self.smbc.run("fstat", move |_| file.fstat()).await
+    }
+
+    /// Close the file, returning the error of a write still queued or
+    /// else any close error
+    pub async fn close(mut self) -> SmbcResult<()> {
+        let flushed = future::poll_fn(|cx| Pin::new(&mut self).poll_flush(cx)).await;
+        let file = self.file.take().expect("the file is only taken on close or drop");
+        let closed = self.smbc.run("close", move |_| file.close()).await;
+        flushed?;
+        closed
+    }
+
+    fn file(&self) -> &SmbcFile {
+        self.file.as_ref().expect("the file is only taken on close or drop")
+    }
+
+    /// Start f on the worker, after giving back the data read ahead
+    fn start<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut SmbcFile) -> IoResult<FileOp> + Send + 'static,
+    {
+        let unread = (self.buf.len() - self.buf_pos) as i64;
+        self.buf.clear();
+        self.buf_pos = 0;
+        let mut file = self.file().clone();
+        self.busy = Some(self.smbc.spawn(move |_| {
+            let res = match unread {
+                0 => f(&mut file),
+                n => file.seek(SeekFrom::Current(-n)).and_then(|_| f(&mut file)),
+            };
+            (res, file.stream_position().ok())
+        }));
+    }
+
+    /// Wait for the call running on the worker, if any, and keep its
+    /// outcome in done (and its data in buf)
+    fn poll_busy(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(busy) = &mut self.busy {
+            let (mut res, pos) = ready!(Pin::new(busy).poll(cx))
+                .unwrap_or_else(|_| (Err(dropped("smbc file")), None));
+            if let Ok(FileOp::Read(data)) = &mut res {
+                self.buf = mem::take(data);
+                self.buf_pos = 0;
+            }
+            self.busy = None;
+            self.done = Some(res);
+            self.pos = pos;
+        }
+        Poll::Ready(())
+    }
+}
+
+impl AsyncRead for AsyncSmbcFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        dst: &mut ReadBuf<'_>,
+    ) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_busy(cx));
+            match this.done.take() {
+                // the read came back, empty at the end of the file
+                Some(Ok(FileOp::Read(_))) => {
+                    fill(&this.buf, &mut this.buf_pos, dst);
+                    return Poll::Ready(Ok(()));
+                }
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                _ => {}
+            }
+            if this.buf_pos < this.buf.len() || dst.remaining() == 0 {
+                fill(&this.buf, &mut this.buf_pos, dst);
+                return Poll::Ready(Ok(()));
+            }
+            let len = dst.remaining().min(MAX_IO);
+            this.start(move |file| {
+                let mut data = vec![0; len];
+                let n = file.read(&mut data)?;
+                data.truncate(n);
+                Ok(FileOp::Read(data))
+            });
+        }
+    }
+}
+
+impl AsyncWrite for AsyncSmbcFile {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, src: &[u8]) -> Poll<IoResult<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_busy(cx));
+        // the outcome of an earlier write was returned when it was queued,
+        // all that is left of it is its error
+        if let Some(Err(e)) = this.done.take() {
+            return Poll::Ready(Err(e));
+        }
+        if src.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        let data = src[..src.len().min(MAX_IO)].to_vec();
+        let n = data.len();
+        this.start(move |file| file.write_all(&data).map(|()| FileOp::Write));
+        Poll::Ready(Ok(n))
+    }
+
+    /// Wait for a write that is still running, returning its error
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_busy(cx));
+        match this.done.take() {
+            Some(Err(e)) => Poll::Ready(Err(e)),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+
+    /// The same as poll_flush, the file is closed by close or on drop
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl AsyncSeek for AsyncSmbcFile {
+    fn start_seek(self: Pin<&mut Self>, pos: SeekFrom) -> IoResult<()> {
+        let this = self.get_mut();
+        if this.busy.is_some() {
+            return Err(Error::other("other file operation is pending, call poll_complete first"));
+        }
+        if let Some(Err(e)) = this.done.take() {
+            return Err(e);
+        }
+        this.start(move |file| file.seek(pos).map(FileOp::Seek));
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<u64>> {
+        let this = self.get_mut();
+        loop {
+            ready!(this.poll_busy(cx));
+            match this.done.take() {
+                Some(Ok(FileOp::Seek(pos))) => return Poll::Ready(Ok(pos)),
+                Some(Err(e)) => return Poll::Ready(Err(e)),
+                _ => {}
+            }
+            if let Some(pos) = this.pos {
+                return Poll::Ready(Ok(pos - (this.buf.len() - this.buf_pos) as u64));
+            }
+            this.start(|file| file.stream_position().map(FileOp::Seek));
+        }
+    }
+}
+
+impl Drop for AsyncSmbcFile {
+    /// Close the file on the worker, not on the async runtime
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = self.smbc.jobs.send(Box::new(move |_| drop(file)));
+        }
+    }
+}
+
+/// An SmbcDirectory for async code, a Stream of its entries read on the
+/// worker of the AsyncSmbc that opened it.  As with the SmbcDirectory
+/// iterator, an Err does not end the stream, except the one of a read the
+/// worker dropped.
+pub struct AsyncSmbcDirectory {
+    smbc: AsyncSmbc,
+    /// the directory, None once dropped
+    dir: Option<SmbcDirectory>,
+    /// the batch being read on the worker
+    busy: Option<oneshot::Receiver<Vec<SmbcResult<SmbcDirEntry>>>>,
+    /// entries read but not yet returned
+    entries: VecDeque<SmbcResult<SmbcDirEntry>>,
+    /// the end of the directory was read
+    done: bool,
+}
+
+impl AsyncSmbcDirectory {
+    fn new(smbc: AsyncSmbc, dir: SmbcDirectory) -> Self {
+        AsyncSmbcDirectory {
+            smbc,
+            dir: Some(dir),
+            busy: None,
+            entries: VecDeque::new(),
+            done: false,
+        }
+    }
+
+    /// Close the directory, returning any close error
+    pub async fn close(mut self) -> SmbcResult<()> {
+        let dir = self.dir.take().expect("the directory is only taken on close or drop");
+        self.smbc.run("closedir", move |_| dir.close()).await
+    }
+}
+
+impl Stream for AsyncSmbcDirectory {
+    type Item = SmbcResult<SmbcDirEntry>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(entry) = this.entries.pop_front() {
+                return Poll::Ready(Some(entry));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match &mut this.busy {
+                Some(busy) => {
+                    let batch = ready!(Pin::new(busy).poll(cx));
+                    this.busy = None;
+                    match batch {
+                        Ok(batch) => {
+                            // a batch ends early at the end of the directory
+                            // or after an error, which ends the stream too
+                            this.done = !matches!(batch.last(), Some(Ok(_)));
+                            this.entries.extend(batch);
+                        }
+                        // the read panicked, it would again: end the stream
+                        // after the error
+                        Err(_) => {
+                            this.done = true;
+                            this.entries.push_back(Err(SmbcError::IoError(dropped("readdir"))));
+                        }
+                    }
+                }
+                None => {
+                    let mut dir =
+                        this.dir.clone().expect("the directory is only taken on close or drop");
+                    this.busy = Some(this.smbc.spawn(move |_| {
+                        let mut batch = vec![];
+                        while batch.len() < DIR_BATCH {
+                            match dir.next() {
+                                Some(Ok(entry)) => batch.push(Ok(entry)),
+                                // return the error before reading on
+                                Some(Err(e)) => {
+                                    batch.push(Err(e));
+                                    break;
+                                }
+                                None => break,
+                            }
+                        }
+                        batch
+                    }));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for AsyncSmbcDirectory {
+    /// Close the directory on the worker, not on the async runtime
+    fn drop(&mut self) {
+        if let Some(dir) = self.dir.take() {
+            let _ = self.smbc.jobs.send(Box::new(move |_| drop(dir)));
+        }
+    }
+}
@@ -0,0 +1,565 @@
+//! `auth` holds the credentials each Smbc context authenticates with
+
+use std::{
+    collections::HashMap,
+    env,
+    ffi::CStr,
+    fmt, fs,
+    io::{Error, ErrorKind},
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    builder::ProtocolRange,
+    error::{SmbcAuthError, SmbcError, SmbcProtocolError, SmbcResult},
+    retry::RetryPolicy,
+    url::redacted,
+};
+use libc::{c_char, c_int, EACCES, ENOTSUP, EPERM, EPROTO, EPROTONOSUPPORT};
+use log::{error, trace};
+
+#[test]
+fn test_copy_to_buf() {
+    let mut buf = [b'x' as c_char; 6];
+    unsafe { copy_to_buf("guest", buf.as_mut_ptr(), buf.len() as c_int) };
+    assert_eq!(&buf, b"guest\0".map(|c| c as c_char).as_ref());
+    unsafe { copy_to_buf("administrator", buf.as_mut_ptr(), buf.len() as c_int) };
+    assert_eq!(&buf, b"admin\0".map(|c| c as c_char).as_ref());
+}
+
+#[test]
+fn test_credentials_debug_hides_password() {
+    let creds = SmbcCredentials::new("CORP", "svc_backup", "hunter2");
+    assert!(!format!("{:?}", creds).contains("hunter2"));
+}
+
+#[test]
+fn test_static_credentials() {
+    let alice = SmbcCredentials::new("CORP", "alice", "a");
+    let bob = SmbcCredentials::new("LAB", "bob", "b");
+    let guest = SmbcCredentials::guest();
+    let creds = StaticCredentials::new()
+        .server("files01", alice.clone())
+        .share("FILES01", "payroll", bob.clone())
+        .fallback(guest.clone());
+    assert_eq!(creds.credentials("Files01", "public"), Some(alice));
+    assert_eq!(creds.credentials("files01", "Payroll"), Some(bob));
+    assert_eq!(creds.credentials("files02", "public"), Some(guest));
+    assert_eq!(StaticCredentials::new().credentials("files01", ""), None);
+}
+
+#[test]
+fn test_env_var_name() {
+    assert_eq!(
+        EnvCredentials::new("SMBC").var(Some("files-01.corp"), "USERNAME"),
+        "SMBC_FILES_01_CORP_USERNAME"
+    );
+    assert_eq!(EnvCredentials::new("SMBC").var(None, "PASSWORD"), "SMBC_PASSWORD");
+}
+
+#[test]
+fn test_parse_auth_file() {
+    let file =
+        "# service account\nusername = svc_backup\npassword=p@ss = word \n\ndomain\t= CORP\n";
+    let creds = parse_auth_file(file).unwrap();
+    assert_eq!(creds, SmbcCredentials::new("CORP", "svc_backup", "p@ss = word"));
+
+    let creds = parse_auth_file("username = guest").unwrap();
+    assert_eq!(creds, SmbcCredentials::new("WORKGROUP", "guest", ""));
+
+    assert!(parse_auth_file("password = x").is_err());
+    assert!(parse_auth_file("username = x\nfoo").is_err());
+}
+
+#[test]
+fn test_auth_method_display() {
+    assert_eq!(AuthMethod::default().to_string(), "NTLM");
+    let method = AuthMethod { nt_hash: true, ..AuthMethod::default() };
+    assert_eq!(method.to_string(), "NTLM (NT hash)");
+    let method = AuthMethod { kerberos: true, ccache: Some(true), ..AuthMethod::default() };
+    assert_eq!(method.to_string(), "Kerberos (ccache)");
+    let method = AuthMethod { kerberos: true, ccache: Some(false), ..AuthMethod::default() };
+    assert_eq!(method.to_string(), "Kerberos");
+    let method =
+        AuthMethod { kerberos: true, fallback_after_kerberos: Some(true), ..AuthMethod::default() };
+    assert_eq!(method.to_string(), "Kerberos, then NTLM");
+}
+
+#[test]
+fn test_protocol_failure() {
+    use crate::builder::SmbProtocol;
+    let url = Path::new("smb://files01/payroll");
+    let mut auth = SmbcAuth::new(Arc::new(SmbcCredentials::guest()), AuthMethod::default());
+    let refused = || Error::from_raw_os_error(ENOTSUP);
+    let reset = || Error::from_raw_os_error(libc::ECONNRESET);
+    assert!(matches!(auth.failure("stat", url, refused()), SmbcError::OperationFailed(_)));
+    auth.protocols = Some(ProtocolRange::only(SmbProtocol::SMB3_11));
+    // without a connection attempt the errors are the operation's
+    assert!(matches!(auth.failure("stat", url, refused()), SmbcError::OperationFailed(_)));
+    assert!(matches!(auth.failure("stat", url, reset()), SmbcError::ConnectionLost(_)));
+    auth.last_attempt = Some(AuthAttempt {
+        server: "files01".to_string(),
+        share: "payroll".to_string(),
+        workgroup: "WORKGROUP".to_string(),
+        username: "guest".to_string(),
+    });
+    // a reset is not a refusal, the server may be restarting
+    assert!(matches!(auth.failure("stat", url, reset()), SmbcError::ConnectionLost(_)));
+    match auth.failure("stat", url, refused()) {
+        SmbcError::ProtocolNegotiationFailed(e) => {
+            assert_eq!(e.server, "files01");
+            assert_eq!(e.range.min, SmbProtocol::SMB3_11);
+        }
+        e => panic!("unexpected {:?}", e),
+    }
+    let missing = Error::from_raw_os_error(libc::ENOENT);
+    assert!(matches!(auth.failure("stat", url, missing), SmbcError::NotFound(_)));
+}
+
+#[test]
+fn test_auth_failure() {
+    let url = Path::new("smb://files01/payroll");
+    let mut auth = SmbcAuth::new(Arc::new(SmbcCredentials::guest()), AuthMethod::default());
+    let denied = || Error::from_raw_os_error(EACCES);
+    assert!(matches!(auth.failure("open", url, denied()), SmbcError::AccessDenied(_)));
+    auth.last_attempt = Some(AuthAttempt {
+        server: "FILES01".to_string(),
+        share: "payroll".to_string(),
+        workgroup: "WORKGROUP".to_string(),
+        username: "guest".to_string(),
+    });
+    match auth.failure("open", url, denied()) {
+        SmbcError::AuthenticationFailed(e) => {
+            assert_eq!((e.op, e.server.as_str(), e.share.as_str()), ("open", "FILES01", "payroll"));
+        }
+        e => panic!("unexpected {:?}", e),
+    }
+}
+
+#[test]
+fn test_split_smb_url() {
+    let split = |s: &str| split_smb_url(Path::new(s));
+    assert_eq!(split("smb://files01/payroll/2019"), ("files01".to_string(), "payroll".to_string()));
+    assert_eq!(split("smb://files01"), ("files01".to_string(), "".to_string()));
+    assert_eq!(split("smb://CORP;bob@files01/"), ("files01".to_string(), "".to_string()));
+}
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+/// How a context authenticates.  The default is NTLM with the password.
+/// The options left None keep the libsmbclient default.
+pub struct AuthMethod {
+    /// try Kerberos first.  The username from the credentials is the
+    /// principal, the ticket comes from the default ccache (kinit)
+    pub kerberos: bool,
+    /// when Kerberos fails, fall back to NTLM with the password
+    /// (libsmbclient does not by default)
+    pub fallback_after_kerberos: Option<bool>,
+    /// use credentials cached by winbind (libsmbclient does by default)
+    pub ccache: Option<bool>,
+    /// the password is the hex encoded NT hash of the password
+    pub nt_hash: bool,
+}
+
+impl fmt::Display for AuthMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ntlm = if self.nt_hash { "NTLM (NT hash)" } else { "NTLM" };
+        let ccache = if self.ccache == Some(true) { " (ccache)" } else { "" };
+        match (self.kerberos, self.fallback_after_kerberos == Some(true)) {
+            (false, _) => write!(f, "{}{}", ntlm, ccache),
+            (true, false) => write!(f, "Kerberos{}", ccache),
+            (true, true) => write!(f, "Kerberos{}, then {}", ccache, ntlm),
+        }
+    }
+}
+
+#[derive(Clone, Eq, PartialEq)]
+/// The workgroup, username and password handed to libsmbclient
+/// whenever a context connects to a server
+pub struct SmbcCredentials {
+    /// workgroup (or domain) of the user
+    pub workgroup: String,
+    /// the username
+    pub username: String,
+    /// the password
+    pub password: String,
+}
+
+impl SmbcCredentials {
+    pub fn new(workgroup: &str, username: &str, password: &str) -> Self {
+        SmbcCredentials {
+            workgroup: workgroup.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+        }
+    }
+
+    /// The guest account of WORKGROUP with an empty password
+    pub fn guest() -> Self {
+        SmbcCredentials::new("WORKGROUP", "guest", "")
+    }
+}
+
+impl Default for SmbcCredentials {
+    fn default() -> Self {
+        SmbcCredentials::guest()
+    }
+}
+
+/// Debug never prints the password
+impl fmt::Debug for SmbcCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmbcCredentials")
+            .field("workgroup", &self.workgroup)
+            .field("username", &self.username)
+            .field("password", &"********")
+            .finish()
+    }
+}
+
+/// Looks up the credentials a context uses for a server and share.
+///
+/// libsmbclient asks whenever it opens a new connection.  Returning None
+/// falls back to the credentials set with SmbcBuilder::credentials, and
+/// without those leaves the context's default workgroup and user in place
+/// with an empty password.
+///
+/// Closures of the form `Fn(&str, &str) -> Option<SmbcCredentials>` are
+/// providers as well.
+pub trait CredentialProvider: Send + Sync {
+    /// @param server    the server being connected to
+    ///
+    /// @param share     the share being connected to (may be empty when
+    ///                  browsing the server itself)
+    fn credentials(&self, server: &str, share: &str) -> Option<SmbcCredentials>;
+}
+
+/// The same credentials for every server
+impl CredentialProvider for SmbcCredentials {
+    fn credentials(&self, _server: &str, _share: &str) -> Option<SmbcCredentials> {
+        Some(self.clone())
+    }
+}
+
+impl<F> CredentialProvider for F
+where
+    F: Fn(&str, &str) -> Option<SmbcCredentials> + Send + Sync,
+{
+    fn credentials(&self, server: &str, share: &str) -> Option<SmbcCredentials> {
+        self(server, share)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Credentials from a fixed map.
+///
+/// Lookups try the server and share first, then the server alone, then
+/// the default.  Server and share names are not case sensitive.
+pub struct StaticCredentials {
+    entries: HashMap<(String, Option<String>), SmbcCredentials>,
+    fallback: Option<SmbcCredentials>,
+}
+
+impl StaticCredentials {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Credentials for every share of server
+    pub fn server(mut self, server: &str, credentials: SmbcCredentials) -> Self {
+        self.entries.insert((server.to_lowercase(), None), credentials);
+        self
+    }
+
+    /// Credentials for a single share of server
+    pub fn share(mut self, server: &str, share: &str, credentials: SmbcCredentials) -> Self {
+        self.entries.insert((server.to_lowercase(), Some(share.to_lowercase())), credentials);
+        self
+    }
+
+    /// Credentials for servers that have no entry
+    pub fn fallback(mut self, credentials: SmbcCredentials) -> Self {
+        self.fallback = Some(credentials);
+        self
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn credentials(&self, server: &str, share: &str) -> Option<SmbcCredentials> {
+        let server = server.to_lowercase();
+        self.entries
+            .get(&(server.clone(), Some(share.to_lowercase())))
+            .or_else(|| self.entries.get(&(server, None)))
+            .or(self.fallback.as_ref())
+            .cloned()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Credentials from environment variables, read on every lookup.
+///
+/// For a prefix of SMBC and the server files01 it reads
+/// SMBC_FILES01_USERNAME, SMBC_FILES01_PASSWORD and SMBC_FILES01_WORKGROUP,
+/// and falls back to SMBC_USERNAME, SMBC_PASSWORD and SMBC_WORKGROUP when
+/// the server has no username of its own.  Characters of the server name
+/// that are not letters or digits become underscores.
+pub struct EnvCredentials {
+    prefix: String,
+}
+
+impl EnvCredentials {
+    pub fn new(prefix: &str) -> Self {
+        EnvCredentials { prefix: prefix.to_string() }
+    }
+
+    /// name of the variable holding key, for server or for every server
+    fn var(&self, server: Option<&str>, key: &str) -> String {
+        match server {
+            Some(server) => {
+                let server: String = server
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_uppercase() } else { '_' })
+                    .collect();
+                format!("{}_{}_{}", self.prefix, server, key)
+            }
+            None => format!("{}_{}", self.prefix, key),
+        }
+    }
+
+    fn lookup(&self, server: Option<&str>) -> Option<SmbcCredentials> {
+        let username = env::var(self.var(server, "USERNAME")).ok()?;
+        let password = env::var(self.var(server, "PASSWORD")).unwrap_or_default();
+        let workgroup =
+            env::var(self.var(server, "WORKGROUP")).unwrap_or_else(|_| "WORKGROUP".to_string());
+        Some(SmbcCredentials { workgroup, username, password })
+    }
+}
+
+impl Default for EnvCredentials {
+    /// Variables prefixed with SMBC
+    fn default() -> Self {
+        EnvCredentials::new("SMBC")
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credentials(&self, server: &str, _share: &str) -> Option<SmbcCredentials> {
+        self.lookup(Some(server)).or_else(|| self.lookup(None))
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Credentials from an smbclient style authentication file (smbclient -A):
+///
+/// ```text
+/// username = <value>
+/// password = <value>
+/// domain   = <value>
+/// ```
+///
+/// Lines starting with # are comments.  The file is read once, when it
+/// is opened; the same credentials are used for every server.
+pub struct AuthFileCredentials {
+    /// where the credentials were read from
+    pub path: PathBuf,
+    credentials: SmbcCredentials,
+}
+
+impl AuthFileCredentials {
+    /// Read and parse an authentication file
+    pub fn open(path: &Path) -> SmbcResult<Self> {
+        let contents = fs::read_to_string(path)?;
+        Ok(AuthFileCredentials {
+            path: path.to_path_buf(),
+            credentials: parse_auth_file(&contents)?,
+        })
+    }
+}
+
+impl CredentialProvider for AuthFileCredentials {
+    fn credentials(&self, _server: &str, _share: &str) -> Option<SmbcCredentials> {
+        Some(self.credentials.clone())
+    }
+}
+
+/// Parse the contents of an smbclient authentication file.
+/// The workgroup defaults to WORKGROUP and the password to empty,
+/// the username is required.
+pub fn parse_auth_file(contents: &str) -> SmbcResult<SmbcCredentials> {
+    let (mut workgroup, mut username, mut password) = (None, None, None);
+    for line in contents.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.find('=') {
+            Some(i) => (line[..i].trim(), line[i + 1..].trim()),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("authentication file line is not key = value: {:?}", line),
+                )
+                .into())
+            }
+        };
+        match key {
+            "username" => username = Some(value.to_string()),
+            "password" => password = Some(value.to_string()),
+            "domain" => workgroup = Some(value.to_string()),
+            _ => {}
+        }
+    }
+    match username {
+        Some(username) => Ok(SmbcCredentials {
+            workgroup: workgroup.unwrap_or_else(|| "WORKGROUP".to_string()),
+            username,
+            password: password.unwrap_or_default(),
+        }),
+        None => {
+            Err(Error::new(ErrorKind::InvalidData, "authentication file has no username").into())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A connection the auth callback was asked for credentials for
+pub(crate) struct AuthAttempt {
+    pub(crate) server: String,
+    pub(crate) share: String,
+    /// the workgroup and username libsmbclient connects with once the
+    /// callback returns, which the connection is cached under
+    pub(crate) workgroup: String,
+    pub(crate) username: String,
+}
+
+/// Authentication state owned by a context.  libsmbclient hands a pointer
+/// to it back to the auth callback through the context user data.
+pub(crate) struct SmbcAuth {
+    pub(crate) provider: Arc<dyn CredentialProvider>,
+    pub(crate) method: AuthMethod,
+    /// the connection of the last auth callback, while it is not known
+    /// to be made (see SmbcPtr::call)
+    pub(crate) last_attempt: Option<AuthAttempt>,
+    /// the SMB dialects the context was limited to, if any
+    pub(crate) protocols: Option<ProtocolRange>,
+    /// the retry policy of the context, if any
+    pub(crate) retry: Option<RetryPolicy>,
+}
+
+impl SmbcAuth {
+    pub(crate) fn new(provider: Arc<dyn CredentialProvider>, method: AuthMethod) -> Self {
+        SmbcAuth { provider, method, last_attempt: None, protocols: None, retry: None }
+    }
+
+    /// Ask the provider for the credentials of server and share and copy
+    /// them into the buffers of an auth callback.  The buffers are left
+    /// alone when it has none (or panics).
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) unsafe fn fill(
+        &mut self,
+        server: &str,
+        share: &str,
+        wg: *mut c_char,
+        wglen: c_int,
+        un: *mut c_char,
+        unlen: c_int,
+        pw: *mut c_char,
+        pwlen: c_int,
+    ) {
+        let lookup =
+            panic::catch_unwind(AssertUnwindSafe(|| self.provider.credentials(server, share)));
+        match lookup {
+            Ok(Some(credentials)) => {
+                trace!(target: "smbc", "credentials: {:?}", &credentials);
+                copy_to_buf(&credentials.workgroup, wg, wglen);
+                copy_to_buf(&credentials.username, un, unlen);
+                copy_to_buf(&credentials.password, pw, pwlen);
+            }
+            Ok(None) => {}
+            Err(_) => error!("credential provider panicked for {}\\{}", server, share),
+        }
+        self.last_attempt = Some(AuthAttempt {
+            server: server.to_string(),
+            share: share.to_string(),
+            workgroup: buf_to_string(wg),
+            username: buf_to_string(un),
+        });
+    }
+
+    /// The error for a failed call of op on url.  When the call failed to
+    /// make the connection the auth callback ran for (last_attempt is
+    /// set), EACCES and EPERM are reported as an authentication failure
+    /// on its server and share, and on a context limited to a protocol
+    /// range the errors of a server rejecting the offered dialects are
+    /// reported as a protocol negotiation failure.  A connection reset is
+    /// not one of them: the auth callback runs before the connection is
+    /// made, so a reset is as likely a restarting server.  Anything else,
+    /// including errors on connections already made, is classified by
+    /// SmbcError::from_errno.
+    pub(crate) fn failure(&self, op: &'static str, url: &Path, err: Error) -> SmbcError {
+        let url_str = || redacted(url);
+        match (err.raw_os_error(), self.protocols, &self.last_attempt) {
+            (Some(ENOTSUP), Some(range), Some(_))
+            | (Some(EPROTONOSUPPORT), Some(range), Some(_))
+            | (Some(EPROTO), Some(range), Some(_)) => {
+                SmbcError::ProtocolNegotiationFailed(SmbcProtocolError {
+                    op,
+                    url: url_str(),
+                    range,
+                    server: split_smb_url(url).0,
+                    source: err,
+                })
+            }
+            (Some(EACCES), _, Some(attempt)) | (Some(EPERM), _, Some(attempt)) => {
+                SmbcError::AuthenticationFailed(SmbcAuthError {
+                    op,
+                    url: url_str(),
+                    method: self.method,
+                    server: attempt.server.clone(),
+                    share: attempt.share.clone(),
+                    source: err,
+                })
+            }
+            _ => SmbcError::from_errno(op, url, err),
+        }
+    }
+}
+
+/// The server and share of an smb://[[domain;]user[:password]@]server/share/path url
+fn split_smb_url(url: &Path) -> (String, String) {
+    let url = url.to_string_lossy();
+    let rest = url.trim_start_matches("smb://");
+    let mut parts = rest.splitn(3, '/');
+    let server = parts.next().unwrap_or("");
+    let server = server.rsplit('@').next().unwrap_or(server);
+    (server.to_string(), parts.next().unwrap_or("").to_string())
+}
+
+/// A credential provider held by SmbcBuilder
+#[derive(Clone)]
+pub(crate) struct SharedProvider(pub(crate) Arc<dyn CredentialProvider>);
+
+impl fmt::Debug for SharedProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CredentialProvider")
+    }
+}
+
+/// The NUL terminated string in a C buffer, empty for none
+unsafe fn buf_to_string(buf: *const c_char) -> String {
+    if buf.is_null() {
+        return String::new();
+    }
+    CStr::from_ptr(buf).to_string_lossy().into_owned()
+}
+
+/// Copy a string into a C buffer of len bytes, truncating it if needed,
+/// and always NUL terminate it
+pub(crate) unsafe fn copy_to_buf(src: &str, buf: *mut c_char, len: c_int) {
+    if buf.is_null() || len <= 0 {
+        return;
+    }
+    let n = src.len().min(len as usize - 1);
+    std::ptr::copy_nonoverlapping(src.as_ptr() as *const c_char, buf, n);
+    *buf.add(n) = 0;
+}
@@ -0,0 +1,148 @@
+//! `browse` lists the workgroups, servers and shares of the network
+
+use std::{
+    ffi::OsString,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    error::SmbcResult,
+    smbc::{Smbc, SmbcDirEntry, SmbcType, URL_NAME},
+};
+use percent_encoding::{percent_encode, utf8_percent_encode};
+
+#[test]
+fn test_visible_share() {
+    let share = |name: &str, s_type| SmbcNetEntry {
+        name: name.to_string(),
+        s_type,
+        comment: String::new(),
+        url: browse_url(&["files01", name]),
+    };
+    assert!(share("reports", SmbcType::FILESHARE).is_visible_share());
+    assert!(share("laser2", SmbcType::PRINTERSHARE).is_visible_share());
+    assert!(!share("ADMIN$", SmbcType::FILESHARE).is_visible_share());
+    assert!(!share("print$", SmbcType::FILESHARE).is_visible_share());
+    assert!(!share("IPC$", SmbcType::IPCSHARE).is_visible_share());
+}
+
+#[test]
+fn test_browse_url() {
+    use std::path::Path;
+    assert_eq!(browse_url(&[]), Path::new("smb://"));
+    assert_eq!(browse_url(&["WORKGROUP"]), Path::new("smb://WORKGROUP"));
+    assert_eq!(browse_url(&["files 01"]), Path::new("smb://files 01"));
+    assert_eq!(browse_url(&["files01", "q1%"]), Path::new("smb://files01/q1%25"));
+}
+
+#[test]
+fn test_net_entry() {
+    let entry = |s_type, name: &[u8]| SmbcDirEntry {
+        s_type,
+        comment: String::new(),
+        path: PathBuf::from(OsString::from_vec(name.to_vec())),
+    };
+    let share = net_entry(&["files01"], entry(SmbcType::FILESHARE, b"caf\xe9 100%"));
+    assert_eq!(share.name, "caf\u{FFFD} 100%");
+    assert_eq!(share.url, Path::new("smb://files01/caf%E9 100%25"));
+    let server = net_entry(&["CORP"], entry(SmbcType::SERVER, b"files\xff"));
+    assert_eq!(server.url, Path::new("smb://files%FF"));
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A workgroup, server or share found by browsing
+pub struct SmbcNetEntry {
+    /// the workgroup, server or share name, for display (U+FFFD in place
+    /// of bytes that are not UTF-8)
+    pub name: String,
+    /// WORKGROUP, SERVER, or the kind of share
+    pub s_type: SmbcType,
+    /// the comment the server gives it
+    pub comment: String,
+    /// the smb url of the workgroup, server or share
+    pub url: PathBuf,
+}
+
+impl SmbcNetEntry {
+    /// Whether a share is listed by default: hidden ($) and IPC shares
+    /// are not
+    fn is_visible_share(&self) -> bool {
+        !self.name.ends_with('$') && self.s_type != SmbcType::IPCSHARE
+    }
+}
+
+impl Smbc {
+    /// The workgroups (and domains) the master browsers know of
+    ///
+    /// @note: browsing needs NetBIOS: SMB1 and a master browser on the
+    ///        network.  Servers that only speak SMB2+ are not listed.
+    pub fn list_workgroups(&self) -> SmbcResult<Vec<SmbcNetEntry>> {
+        self.browse(&[], &[SmbcType::WORKGROUP])
+    }
+
+    /// The servers of a workgroup (see list_workgroups for what browsing
+    /// needs)
+    pub fn list_servers(&self, workgroup: &str) -> SmbcResult<Vec<SmbcNetEntry>> {
+        self.browse(&[workgroup], &[SmbcType::SERVER])
+    }
+
+    /// The file, printer and comms shares of a server, leaving out
+    /// hidden shares (names ending in $) and IPC shares.
+    ///
+    /// @param server    the server name or address
+    pub fn list_shares(&self, server: &str) -> SmbcResult<Vec<SmbcNetEntry>> {
+        let mut shares = self.list_all_shares(server)?;
+        shares.retain(SmbcNetEntry::is_visible_share);
+        Ok(shares)
+    }
+
+    /// Every share of a server, hidden and IPC shares included
+    pub fn list_all_shares(&self, server: &str) -> SmbcResult<Vec<SmbcNetEntry>> {
+        let types =
+            [SmbcType::FILESHARE, SmbcType::PRINTERSHARE, SmbcType::COMMSSHARE, SmbcType::IPCSHARE];
+        self.browse(&[server], &types)
+    }
+
+    /// The entries of the given types in the browse url of names
+    fn browse(&self, names: &[&str], types: &[SmbcType]) -> SmbcResult<Vec<SmbcNetEntry>> {
+        let dir = self.opendir(&browse_url(names))?;
+        let mut entries = vec![];
+        while let Some(entry) = dir.readdir()? {
+            if types.contains(&entry.s_type) {
+                entries.push(net_entry(names, entry));
+            }
+        }
+        dir.close()?;
+        Ok(entries)
+    }
+}
+
+/// smb://, smb://workgroup, smb://server or smb://server/share
+fn browse_url(names: &[&str]) -> PathBuf {
+    let names: Vec<_> =
+        names.iter().map(|n| utf8_percent_encode(n, URL_NAME).to_string()).collect();
+    PathBuf::from(format!("smb://{}", names.join("/")))
+}
+
+/// The browse url of parent with name under it.  The name is
+/// percent-encoded byte for byte, so names that are not UTF-8 survive.
+fn net_url(parent: &[&str], name: &Path) -> PathBuf {
+    let mut url = browse_url(parent).into_os_string().into_vec();
+    if !parent.is_empty() {
+        url.push(b'/');
+    }
+    url.extend(percent_encode(name.as_os_str().as_bytes(), URL_NAME).flat_map(str::bytes));
+    PathBuf::from(OsString::from_vec(url))
+}
+
+/// The entry found browsing names.  Workgroups and servers have urls of
+/// their own, shares are under their server.
+fn net_entry(names: &[&str], entry: SmbcDirEntry) -> SmbcNetEntry {
+    let url = match entry.s_type {
+        SmbcType::WORKGROUP | SmbcType::SERVER => net_url(&[], &entry.path),
+        _ => net_url(&names[..names.len().min(1)], &entry.path),
+    };
+    let name = entry.display_name().into_owned();
+    SmbcNetEntry { name, s_type: entry.s_type, comment: entry.comment, url }
+}
@@ -0,0 +1,728 @@
+//! `builder` configures a libsmbclient context before it is initialized
+
+use std::{
+    env,
+    ffi::{CStr, CString},
+    fmt, fs,
+    io::{Error, ErrorKind, Write},
+    os::unix::{ffi::OsStrExt, fs::OpenOptionsExt},
+    path::{Path, PathBuf},
+    process, ptr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    auth::{AuthMethod, CredentialProvider, SharedProvider, SmbcAuth, SmbcCredentials},
+    error::{SmbcError, SmbcResult},
+    retry::RetryPolicy,
+    smbc::Smbc,
+};
+use libc::{c_char, c_int, c_void};
+use log::{log, trace, Level};
+use rust_smbclient_sys::*;
+
+#[test]
+fn test_protocol_range() {
+    let range = ProtocolRange::new(SmbProtocol::SMB2_02, SmbProtocol::SMB3_11);
+    assert_eq!(range.to_string(), "SMB2_02..SMB3_11");
+    assert!(range.contains(SmbProtocol::SMB3_00));
+    assert!(!range.contains(SmbProtocol::NT1));
+    assert!(range.validate().is_ok());
+    assert_eq!(ProtocolRange::only(SmbProtocol::SMB3_11).to_string(), "SMB3_11");
+    assert!(ProtocolRange::new(SmbProtocol::SMB3_11, SmbProtocol::NT1).validate().is_err());
+}
+
+#[test]
+fn test_smb_conf() {
+    assert_eq!(SmbcBuilder::new().smb_conf().unwrap(), None);
+    let builder = SmbcBuilder::new()
+        .config_file(Path::new("/etc/samba/smb.conf"))
+        .parameter("client signing", "required");
+    assert_eq!(
+        builder.smb_conf().unwrap().unwrap(),
+        "[global]\n\tinclude = /etc/samba/smb.conf\n\tclient signing = required\n"
+    );
+    assert!(builder.clone().parameter("workgroup", "CORP\n\tinclude = /tmp/x").smb_conf().is_err());
+    assert!(builder.parameter("name\r", "x").smb_conf().is_err());
+}
+
+#[test]
+fn test_provider_fallback() {
+    let alice = SmbcCredentials::new("CORP", "alice", "a");
+    let guest = SmbcCredentials::guest();
+    let only_files01 = |server: &str, _share: &str| match server {
+        "files01" => Some(SmbcCredentials::new("CORP", "alice", "a")),
+        _ => None,
+    };
+    let builder = SmbcBuilder::new().credential_provider(Arc::new(only_files01));
+    let provider = builder.provider(guest.clone());
+    assert_eq!(provider.credentials("files01", ""), Some(alice.clone()));
+    assert_eq!(provider.credentials("files02", ""), None);
+    let provider = builder.credentials(guest.clone()).provider(guest.clone());
+    assert_eq!(provider.credentials("files01", ""), Some(alice));
+    assert_eq!(provider.credentials("files02", ""), Some(guest));
+}
+
+#[test]
+fn test_samba_log_level() {
+    assert_eq!(samba_log_level(0), Level::Error);
+    assert_eq!(samba_log_level(1), Level::Warn);
+    assert_eq!(samba_log_level(3), Level::Info);
+    assert_eq!(samba_log_level(5), Level::Debug);
+    assert_eq!(samba_log_level(10), Level::Trace);
+}
+
+/// counter used to give every generated smb.conf file a unique name
+static CONF_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Where libsmbclient writes its debug output
+pub enum SmbcLogTarget {
+    /// libsmbclient's default, standard output
+    Stdout,
+    /// standard error
+    Stderr,
+    /// `log` records under the "smbc" target (see `samba_log_level`)
+    Log,
+}
+
+/// The log::Level of a Samba debug level: 0 is an error, 1 a warning,
+/// 2 and 3 are informational and everything above is debug (up to 5)
+/// or trace output
+pub fn samba_log_level(level: c_int) -> Level {
+    match level {
+        i32::MIN..=0 => Level::Error,
+        1 => Level::Warn,
+        2..=3 => Level::Info,
+        4..=5 => Level::Debug,
+        _ => Level::Trace,
+    }
+}
+
+/// libsmbclient log callback, forwards each debug message to `log`
+unsafe extern "C" fn log_callback(_private: *mut c_void, level: c_int, msg: *const c_char) {
+    if msg.is_null() {
+        return;
+    }
+    let msg = CStr::from_ptr(msg).to_string_lossy();
+    let msg = msg.trim_end();
+    if !msg.is_empty() {
+        log!(target: "smbc", samba_log_level(level), "{}", msg);
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Share mode requested when files are opened (smbc_share_mode)
+pub enum SmbcShareMode {
+    DenyDos = 0,
+    DenyAll = 1,
+    DenyWrite = 2,
+    DenyRead = 3,
+    DenyNone = 4,
+    DenyFcb = 7,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// SMB3 encryption of the connections a context makes
+/// (smbc_smb_encrypt_level)
+pub enum EncryptionLevel {
+    /// never encrypt (unless the server insists)
+    None = 0,
+    /// encrypt when the server supports it, otherwise connect in the clear
+    Request = 1,
+    /// refuse to connect to servers that cannot encrypt
+    Require = 2,
+}
+
+impl From<u32> for EncryptionLevel {
+    fn from(level: u32) -> Self {
+        match level {
+            1 => EncryptionLevel::Request,
+            2 => EncryptionLevel::Require,
+            _ => EncryptionLevel::None,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+/// Client SMB signing, the smb.conf `client signing` parameter
+pub enum SigningPolicy {
+    /// never sign
+    Disabled,
+    /// sign only when the server requires it
+    IfRequired,
+    /// sign when the server allows it
+    Desired,
+    /// refuse servers that do not sign
+    Required,
+}
+
+impl SigningPolicy {
+    /// the smb.conf value
+    fn as_str(self) -> &'static str {
+        match self {
+            SigningPolicy::Disabled => "disabled",
+            SigningPolicy::IfRequired => "if_required",
+            SigningPolicy::Desired => "desired",
+            SigningPolicy::Required => "required",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+/// An SMB dialect, oldest first, named as in the smb.conf
+/// `client min protocol`/`client max protocol` parameters
+pub enum SmbProtocol {
+    /// SMB1 (the NT LM 0.12 dialect)
+    NT1,
+    SMB2_02,
+    SMB2_10,
+    SMB3_00,
+    SMB3_02,
+    SMB3_11,
+}
+
+impl SmbProtocol {
+    /// the smb.conf name
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SmbProtocol::NT1 => "NT1",
+            SmbProtocol::SMB2_02 => "SMB2_02",
+            SmbProtocol::SMB2_10 => "SMB2_10",
+            SmbProtocol::SMB3_00 => "SMB3_00",
+            SmbProtocol::SMB3_02 => "SMB3_02",
+            SmbProtocol::SMB3_11 => "SMB3_11",
+        }
+    }
+}
+
+impl fmt::Display for SmbProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+/// The SMB dialects a context may negotiate, min and max included
+pub struct ProtocolRange {
+    pub min: SmbProtocol,
+    pub max: SmbProtocol,
+}
+
+impl ProtocolRange {
+    pub fn new(min: SmbProtocol, max: SmbProtocol) -> Self {
+        ProtocolRange { min, max }
+    }
+
+    /// Negotiate exactly one dialect
+    pub fn only(protocol: SmbProtocol) -> Self {
+        ProtocolRange { min: protocol, max: protocol }
+    }
+
+    /// Whether protocol lies within the range
+    pub fn contains(&self, protocol: SmbProtocol) -> bool {
+        self.min <= protocol && protocol <= self.max
+    }
+
+    /// Error if min is newer than max
+    fn validate(&self) -> SmbcResult<()> {
+        if self.min > self.max {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "protocol range {} is empty, {} is newer than {}",
+                    self, self.min, self.max
+                ),
+            )
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for ProtocolRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min == self.max {
+            write!(f, "{}", self.min)
+        } else {
+            write!(f, "{}..{}", self.min, self.max)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Builder for an Smbc context.
+///
+/// Every option left unset keeps the libsmbclient default.
+///
+/// ```no_run
+/// use rust_smb::SmbcBuilder;
+///
+/// let smbc = SmbcBuilder::new()
+///     .debug_level(1)
+///     .netbios_name("CRAWLER01")
+///     .workgroup("CORP")
+///     .one_share_per_server(true)
+///     .build()
+///     .unwrap();
+/// ```
+pub struct SmbcBuilder {
+    debug_level: i32,
+    log_target: Option<SmbcLogTarget>,
+    netbios_name: Option<String>,
+    workgroup: Option<String>,
+    user: Option<String>,
+    credentials: Option<SmbcCredentials>,
+    credential_provider: Option<SharedProvider>,
+    auth_method: AuthMethod,
+    timeout: Option<i32>,
+    port: Option<u16>,
+    one_share_per_server: Option<bool>,
+    browse_max_lmb_count: Option<i32>,
+    case_sensitive: Option<bool>,
+    full_time_names: Option<bool>,
+    url_encode_readdir_entries: Option<bool>,
+    no_auto_anonymous_login: Option<bool>,
+    open_share_mode: Option<SmbcShareMode>,
+    encryption_level: Option<EncryptionLevel>,
+    protocols: Option<ProtocolRange>,
+    retry: Option<RetryPolicy>,
+    config_file: Option<PathBuf>,
+    parameters: Vec<(String, String)>,
+}
+
+impl SmbcBuilder {
+    /// A builder with every option left at the libsmbclient default
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Samba debug level (0 is quiet, 10 is everything)
+    pub fn debug_level(mut self, level: i32) -> Self {
+        self.debug_level = level;
+        self
+    }
+
+    /// Where the debug output is written, by default wherever the last
+    /// context built with a target set it.
+    ///
+    /// @note: libsmbclient has a single debug backend, so the last context
+    /// built with a target decides it for every context in the process.
+    pub fn log_target(mut self, target: SmbcLogTarget) -> Self {
+        self.log_target = Some(target);
+        self
+    }
+
+    /// NetBIOS name this client announces itself as
+    pub fn netbios_name(mut self, name: &str) -> Self {
+        self.netbios_name = Some(name.to_string());
+        self
+    }
+
+    /// Default workgroup of the context
+    pub fn workgroup(mut self, workgroup: &str) -> Self {
+        self.workgroup = Some(workgroup.to_string());
+        self
+    }
+
+    /// Default username of the context
+    pub fn user(mut self, user: &str) -> Self {
+        self.user = Some(user.to_string());
+        self
+    }
+
+    /// Credentials this context authenticates with.  They belong to the
+    /// context alone; when unset the ones from Smbc::set_data are copied.
+    /// With a credential provider they are used for the servers and
+    /// shares it has no credentials for.
+    pub fn credentials(mut self, credentials: SmbcCredentials) -> Self {
+        self.credentials = Some(credentials);
+        self
+    }
+
+    /// Look up the credentials for each server and share with provider
+    /// instead of always using the same ones
+    pub fn credential_provider(mut self, provider: Arc<dyn CredentialProvider>) -> Self {
+        self.credential_provider = Some(SharedProvider(provider));
+        self
+    }
+
+    /// Authenticate with Kerberos.  The username of the credentials is
+    /// used as the principal and the ticket is taken from the default
+    /// credential cache (kinit).
+    pub fn use_kerberos(mut self, enable: bool) -> Self {
+        self.auth_method.kerberos = enable;
+        self
+    }
+
+    /// Fall back to NTLM with the password when Kerberos fails
+    pub fn fallback_after_kerberos(mut self, enable: bool) -> Self {
+        self.auth_method.fallback_after_kerberos = Some(enable);
+        self
+    }
+
+    /// Use the credentials cached by winbind
+    pub fn use_ccache(mut self, enable: bool) -> Self {
+        self.auth_method.ccache = Some(enable);
+        self
+    }
+
+    /// Treat passwords as the hex encoded NT hash of the password
+    /// (pass-the-hash), for accounts whose password is never known
+    pub fn use_nt_hash(mut self, enable: bool) -> Self {
+        self.auth_method.nt_hash = enable;
+        self
+    }
+
+    /// Set every authentication option at once
+    pub fn auth_method(mut self, method: AuthMethod) -> Self {
+        self.auth_method = method;
+        self
+    }
+
+    /// Timeout for server connections and requests, in milliseconds
+    pub fn timeout(mut self, millis: i32) -> Self {
+        self.timeout = Some(millis);
+        self
+    }
+
+    /// Port used to reach servers (0 tries 445 then 139)
+    pub fn port(mut self, port: u16) -> Self {
+        self.port = Some(port);
+        self
+    }
+
+    /// Use a single connection per server for all of its shares
+    pub fn one_share_per_server(mut self, enable: bool) -> Self {
+        self.one_share_per_server = Some(enable);
+        self
+    }
+
+    /// Number of local master browsers queried when listing workgroups.
+    /// 0 queries all of them, 1 only the master browser.
+    pub fn browse_max_lmb_count(mut self, count: i32) -> Self {
+        self.browse_max_lmb_count = Some(count);
+        self
+    }
+
+    /// Tell servers that path names are case sensitive
+    pub fn case_sensitive(mut self, enable: bool) -> Self {
+        self.case_sensitive = Some(enable);
+        self
+    }
+
+    /// Use full names (CREATE_TIME instead of C_TIME etc.) for the
+    /// time attributes of system.dos_attr.*
+    pub fn full_time_names(mut self, enable: bool) -> Self {
+        self.full_time_names = Some(enable);
+        self
+    }
+
+    /// Percent-encode the names libsmbclient returns from readdir.
+    /// SmbcDirectory decodes them again, so entry names are the same
+    /// either way.
+    pub fn url_encode_readdir_entries(mut self, enable: bool) -> Self {
+        self.url_encode_readdir_entries = Some(enable);
+        self
+    }
+
+    /// Do not retry as the anonymous user when authentication fails
+    pub fn no_auto_anonymous_login(mut self, enable: bool) -> Self {
+        self.no_auto_anonymous_login = Some(enable);
+        self
+    }
+
+    /// Share mode requested when opening files
+    pub fn open_share_mode(mut self, mode: SmbcShareMode) -> Self {
+        self.open_share_mode = Some(mode);
+        self
+    }
+
+    /// Encryption of the connections this context makes
+    pub fn encryption_level(mut self, level: EncryptionLevel) -> Self {
+        self.encryption_level = Some(level);
+        self
+    }
+
+    /// The SMB dialects the context may negotiate.  Connecting to a server
+    /// that speaks none of them fails with
+    /// SmbcError::ProtocolNegotiationFailed.
+    ///
+    /// @note: libsmbclient stores this as the `client min protocol` and
+    /// `client max protocol` smb.conf parameters, so it applies to the
+    /// whole process (see `parameter`).
+    pub fn protocol_range(mut self, range: ProtocolRange) -> Self {
+        self.protocols = Some(range);
+        self
+    }
+
+    /// Retry the calls that are safe to repeat when they fail with a
+    /// transient error (see RetryPolicy).  By default nothing is retried.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// SMB signing requirements.
+    ///
+    /// @note: libsmbclient has no per context setting for this; it is the
+    /// `client signing` smb.conf parameter, which applies to the whole
+    /// process (see `parameter`).
+    pub fn signing(self, policy: SigningPolicy) -> Self {
+        self.parameter("client signing", policy.as_str())
+    }
+
+    /// Character set used for local (unix) file names: the names
+    /// directory listings return and the names in smb urls are converted
+    /// from and to it (UTF-8 by default).  Names the charset cannot hold
+    /// fail to convert.
+    ///
+    /// @note: like every smb.conf parameter this applies to the whole
+    /// process (see `parameter`).
+    pub fn unix_charset(self, charset: &str) -> Self {
+        self.parameter("unix charset", charset)
+    }
+
+    /// smb.conf file to load once the context is initialized
+    pub fn config_file(mut self, path: &Path) -> Self {
+        self.config_file = Some(path.to_path_buf());
+        self
+    }
+
+    /// Set any smb.conf `[global]` parameter.
+    ///
+    /// @note: libsmbclient keeps its smb.conf parameters in process wide
+    /// state, so these apply to every context in the process, not only
+    /// the one being built.  Loading them also replaces the smb.conf that
+    /// was loaded before; set `config_file` to keep it (it is included
+    /// ahead of these parameters).
+    pub fn parameter(mut self, name: &str, value: &str) -> Self {
+        self.parameters.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Create and initialize the context
+    ///
+    /// @return         a new Smbc context with the configured options and the
+    ///                 credentials (or the ones set by Smbc::set_data).
+    ///                 Error should it fail.
+    pub fn build(&self) -> SmbcResult<Smbc> {
+        let netbios_name =
+            self.netbios_name.as_ref().map(|n| CString::new(n.as_str())).transpose()?;
+        let workgroup = self.workgroup.as_ref().map(|w| CString::new(w.as_str())).transpose()?;
+        let user = self.user.as_ref().map(|u| CString::new(u.as_str())).transpose()?;
+        let credentials = self.credentials.clone().unwrap_or_else(Smbc::default_credentials);
+        let (wg, un, pw) = (
+            CString::new(credentials.workgroup.as_str())?,
+            CString::new(credentials.username.as_str())?,
+            CString::new(credentials.password.as_str())?,
+        );
+        let provider = self.provider(credentials);
+        let protocols = match &self.protocols {
+            Some(range) => {
+                range.validate()?;
+                Some((range, CString::new(range.min.as_str())?, CString::new(range.max.as_str())?))
+            }
+            None => None,
+        };
+        let mut auth = Box::new(SmbcAuth::new(provider, self.auth_method));
+        auth.protocols = self.protocols;
+        auth.retry = self.retry.clone();
+        let conf = self.smb_conf()?;
+        let config_file = self
+            .config_file
+            .as_ref()
+            .map(|path| CString::new(path.as_os_str().as_bytes()))
+            .transpose()?;
+        unsafe {
+            smbc_init(Some(Smbc::set_data_wrapper), self.debug_level);
+            let ctx = smbc_new_context();
+            if ctx.is_null() {
+                return Err(Error::last_os_error().into());
+            }
+            smbc_setDebug(ctx, self.debug_level);
+            // the callback is process wide, a context built for another
+            // target unsets the one an earlier context set
+            if let Some(target) = self.log_target {
+                smbc_setOptionDebugToStderr(ctx, c_int::from(target == SmbcLogTarget::Stderr));
+                let callback = match target {
+                    SmbcLogTarget::Log => Some(log_callback as _),
+                    SmbcLogTarget::Stdout | SmbcLogTarget::Stderr => None,
+                };
+                smbc_setLogCallback(ctx, ptr::null_mut(), callback);
+            }
+            smbc_setOptionUserData(ctx, &*auth as *const SmbcAuth as *mut c_void);
+            smbc_setFunctionAuthData(ctx, Some(Smbc::set_data_wrapper));
+            smbc_setFunctionAuthDataWithContext(ctx, Some(Smbc::auth_wrapper));
+            if let Some(name) = &netbios_name {
+                smbc_setNetbiosName(ctx, name.as_ptr() as *mut c_char);
+            }
+            if let Some(workgroup) = &workgroup {
+                smbc_setWorkgroup(ctx, workgroup.as_ptr() as *mut c_char);
+            }
+            if let Some(user) = &user {
+                smbc_setUser(ctx, user.as_ptr() as *mut c_char);
+            }
+            if let Some(timeout) = self.timeout {
+                smbc_setTimeout(ctx, timeout);
+            }
+            if let Some(port) = self.port {
+                smbc_setPort(ctx, port);
+            }
+            if let Some(enable) = self.one_share_per_server {
+                smbc_setOptionOneSharePerServer(ctx, c_int::from(enable));
+            }
+            if let Some(count) = self.browse_max_lmb_count {
+                smbc_setOptionBrowseMaxLmbCount(ctx, count);
+            }
+            if let Some(enable) = self.case_sensitive {
+                smbc_setOptionCaseSensitive(ctx, c_int::from(enable));
+            }
+            if let Some(enable) = self.full_time_names {
+                smbc_setOptionFullTimeNames(ctx, c_int::from(enable));
+            }
+            if let Some(enable) = self.url_encode_readdir_entries {
+                smbc_setOptionUrlEncodeReaddirEntries(ctx, c_int::from(enable));
+            }
+            if let Some(enable) = self.no_auto_anonymous_login {
+                smbc_setOptionNoAutoAnonymousLogin(ctx, c_int::from(enable));
+            }
+            smbc_setOptionUseKerberos(ctx, c_int::from(self.auth_method.kerberos));
+            if let Some(enable) = self.auth_method.fallback_after_kerberos {
+                smbc_setOptionFallbackAfterKerberos(ctx, c_int::from(enable));
+            }
+            if let Some(enable) = self.auth_method.ccache {
+                smbc_setOptionUseCCache(ctx, c_int::from(enable));
+            }
+            smbc_setOptionUseNTHash(ctx, c_int::from(self.auth_method.nt_hash));
+            if let Some(mode) = self.open_share_mode {
+                smbc_setOptionOpenShareMode(ctx, mode as _);
+            }
+            if let Some(level) = self.encryption_level {
+                smbc_setOptionSmbEncryptionLevel(ctx, level as _);
+            }
+            if let Some((range, min, max)) = &protocols {
+                if smbc_setOptionProtocols(ctx, min.as_ptr(), max.as_ptr()) == 0 {
+                    trace!(target: "smbc", "smbc_setOptionProtocols {} failed", range);
+                    smbc_free_context(ctx, 1 as c_int);
+                    return Err(Error::new(
+                        ErrorKind::InvalidInput,
+                        format!("libsmbclient rejected protocol range {}", range),
+                    )
+                    .into());
+                }
+            }
+            smbc_set_credentials_with_fallback(ctx, wg.as_ptr(), un.as_ptr(), pw.as_ptr());
+
+            if smbc_init_context(ctx).is_null() {
+                let e = Error::last_os_error();
+                trace!(target: "smbc", "smbc_init failed {:?}", e);
+                smbc_free_context(ctx, 1 as c_int);
+                return Err(e.into());
+            }
+            if let Err(e) = load_config(ctx, conf.as_deref(), config_file.as_deref()) {
+                trace!(target: "smbc", "smbc_setConfiguration failed {:?}", e);
+                smbc_free_context(ctx, 1 as c_int);
+                return Err(e);
+            }
+            smbc_set_context(ctx);
+            Smbc::from_ptr(ctx, auth)
+        }
+    }
+
+    /// What the auth callback asks for credentials: the provider, falling
+    /// back to the credentials set on the builder, or else credentials
+    fn provider(&self, credentials: SmbcCredentials) -> Arc<dyn CredentialProvider> {
+        match (&self.credential_provider, &self.credentials) {
+            (Some(SharedProvider(provider)), Some(fallback)) => {
+                let (provider, fallback) = (Arc::clone(provider), fallback.clone());
+                Arc::new(move |server: &str, share: &str| {
+                    provider.credentials(server, share).or_else(|| Some(fallback.clone()))
+                })
+            }
+            (Some(SharedProvider(provider)), None) => Arc::clone(provider),
+            (None, _) => Arc::new(credentials),
+        }
+    }
+
+    /// The smb.conf holding the parameters, including the configured file
+    /// first, or None when no parameter is set.  Names and values are
+    /// written as they are, so ones with line breaks are refused.
+    fn smb_conf(&self) -> SmbcResult<Option<String>> {
+        if self.parameters.is_empty() {
+            return Ok(None);
+        }
+        let include = self
+            .config_file
+            .as_ref()
+            .map(|path| ("include".to_string(), path.display().to_string()));
+        let mut conf = String::from("[global]\n");
+        for (name, value) in include.iter().chain(&self.parameters) {
+            if name.contains(&['\n', '\r'][..]) || value.contains(&['\n', '\r'][..]) {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("smb.conf parameter {:?} = {:?} spans more than one line", name, value),
+                )
+                .into());
+            }
+            conf.push_str(&format!("\t{} = {}\n", name, value));
+        }
+        Ok(Some(conf))
+    }
+}
+
+/// Load the smb.conf of an initialized context: conf, written to a
+/// temporary file that is removed once it is loaded, else config_file
+unsafe fn load_config(
+    ctx: *mut SMBCCTX,
+    conf: Option<&str>,
+    config_file: Option<&CStr>,
+) -> SmbcResult<()> {
+    let set_configuration = |path: &CStr| {
+        if smbc_setConfiguration(ctx, path.as_ptr()) < 0 {
+            return Err(Error::last_os_error().into());
+        }
+        Ok(())
+    };
+    match (conf, config_file) {
+        (Some(conf), _) => {
+            let path = write_temp_conf(conf)?;
+            let res = CString::new(path.as_os_str().as_bytes())
+                .map_err(SmbcError::from)
+                .and_then(|path| set_configuration(&path));
+            let _ = fs::remove_file(&path);
+            res
+        }
+        (None, Some(path)) => set_configuration(path),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Write conf to a new file in the temporary directory that only this
+/// user can read.  An existing file (or symlink) is never written through,
+/// the next name is tried instead.
+fn write_temp_conf(conf: &str) -> SmbcResult<PathBuf> {
+    loop {
+        let path = env::temp_dir().join(format!(
+            "rust-smb-{}-{}.conf",
+            process::id(),
+            CONF_COUNTER.fetch_add(1, Ordering::SeqCst)
+        ));
+        let file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&path);
+        match file {
+            Ok(mut file) => {
+                trace!(target: "smbc", "writing smb.conf parameters to {:?}", path);
+                if let Err(e) = file.write_all(conf.as_bytes()) {
+                    let _ = fs::remove_file(&path);
+                    return Err(e.into());
+                }
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
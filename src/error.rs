@@ -1,12 +1,266 @@
+use crate::{auth::AuthMethod, builder::ProtocolRange, url::redacted};
 use derive_error as de;
-use std::{ffi, io, result};
+use libc::{
+    EACCES, EBUSY, ECONNABORTED, ECONNREFUSED, ECONNRESET, EEXIST, EHOSTDOWN, EHOSTUNREACH,
+    ENETDOWN, ENETRESET, ENETUNREACH, ENODEV, ENOENT, ENOTCONN, ENOTEMPTY, EPERM, EPIPE, ETIME,
+    ETIMEDOUT, ETXTBSY,
+};
+use std::{error, ffi, fmt, io, path::Path, result};
 
 pub type SmbcResult<T> = result::Result<T, SmbcError>;
 
+#[test]
+fn test_from_errno() {
+    let url = Path::new("smb://files01/payroll/2019.xlsx");
+    let err = |errno| SmbcError::from_errno("stat", url, io::Error::from_raw_os_error(errno));
+    assert!(matches!(err(ENOENT), SmbcError::NotFound(_)));
+    assert!(matches!(err(ENODEV), SmbcError::NotFound(_)));
+    assert!(matches!(err(EACCES), SmbcError::AccessDenied(_)));
+    assert!(matches!(err(EEXIST), SmbcError::AlreadyExists(_)));
+    assert!(matches!(err(ENOTEMPTY), SmbcError::NotEmpty(_)));
+    assert!(matches!(err(EBUSY), SmbcError::SharingViolation(_)));
+    assert!(matches!(err(ECONNRESET), SmbcError::ConnectionLost(_)));
+    assert!(matches!(err(ETIMEDOUT), SmbcError::Timeout(_)));
+    assert!(matches!(err(libc::EINVAL), SmbcError::OperationFailed(_)));
+
+    let e = err(ENOENT);
+    assert_eq!(e.op(), Some("stat"));
+    assert_eq!(e.url(), Some("smb://files01/payroll/2019.xlsx"));
+    assert_eq!(e.raw_os_error(), Some(ENOENT));
+    assert!(e.to_string().starts_with("stat smb://files01/payroll/2019.xlsx: "));
+}
+
 #[derive(Debug, de::Error)]
 pub enum SmbcError {
     FFIError(ffi::NulError),
     IoError(io::Error),
     #[error(msg_embedded, non_std, no_from)]
     SmbcXAttrError(String),
+    /// The file, directory, share or server does not exist (ENOENT, ENODEV)
+    #[error(no_from, msg_embedded)]
+    NotFound(SmbcOpError),
+    /// The server denied access (EACCES, EPERM)
+    #[error(no_from, msg_embedded)]
+    AccessDenied(SmbcOpError),
+    /// The target already exists (EEXIST)
+    #[error(no_from, msg_embedded)]
+    AlreadyExists(SmbcOpError),
+    /// The directory is not empty (ENOTEMPTY)
+    #[error(no_from, msg_embedded)]
+    NotEmpty(SmbcOpError),
+    /// Another open of the file conflicts with this one (EBUSY, ETXTBSY)
+    #[error(no_from, msg_embedded)]
+    SharingViolation(SmbcOpError),
+    /// The server refused the credentials
+    #[error(no_from, msg_embedded)]
+    AuthenticationFailed(SmbcAuthError),
+    /// The server speaks none of the configured SMB dialects
+    #[error(no_from, msg_embedded)]
+    ProtocolNegotiationFailed(SmbcProtocolError),
+    /// The connection to the server could not be made or was dropped
+    #[error(no_from, msg_embedded)]
+    ConnectionLost(SmbcOpError),
+    /// The server did not answer in time (ETIMEDOUT)
+    #[error(no_from, msg_embedded)]
+    Timeout(SmbcOpError),
+    /// The server sent something that could not be decoded
+    #[error(no_from, msg_embedded)]
+    Parse(SmbcOpError),
+    /// Any other failed libsmbclient call
+    #[error(no_from, msg_embedded)]
+    OperationFailed(SmbcOpError),
+    /// The operation found the context poisoned by a panic on another
+    /// thread (see Smbc::clear_poison)
+    #[error(non_std, no_from, msg = "smbc context poisoned by a panic on another thread")]
+    ContextPoisoned(&'static str),
+}
+
+impl SmbcError {
+    /// Classify the error of a failed libsmbclient call by its errno
+    ///
+    /// @param op       the operation that failed (stat, open, setxattr...)
+    ///
+    /// @param url      the smb url it was called on
+    ///
+    /// @param source   the error libsmbclient left in errno
+    pub fn from_errno(op: &'static str, url: &Path, source: io::Error) -> SmbcError {
+        let e = SmbcOpError::new(op, url, source);
+        match e.source.raw_os_error() {
+            Some(ENOENT) | Some(ENODEV) => SmbcError::NotFound(e),
+            Some(EACCES) | Some(EPERM) => SmbcError::AccessDenied(e),
+            Some(EEXIST) => SmbcError::AlreadyExists(e),
+            Some(ENOTEMPTY) => SmbcError::NotEmpty(e),
+            Some(EBUSY) | Some(ETXTBSY) => SmbcError::SharingViolation(e),
+            Some(ECONNRESET) | Some(ECONNABORTED) | Some(ECONNREFUSED) | Some(ENOTCONN)
+            | Some(EPIPE) | Some(ENETRESET) | Some(ENETDOWN) | Some(ENETUNREACH)
+            | Some(EHOSTUNREACH) | Some(EHOSTDOWN) => SmbcError::ConnectionLost(e),
+            Some(ETIMEDOUT) | Some(ETIME) => SmbcError::Timeout(e),
+            _ => SmbcError::OperationFailed(e),
+        }
+    }
+
+    /// The operation that failed, for errors of a libsmbclient call
+    pub fn op(&self) -> Option<&'static str> {
+        match self {
+            SmbcError::AuthenticationFailed(e) => Some(e.op),
+            SmbcError::ProtocolNegotiationFailed(e) => Some(e.op),
+            SmbcError::ContextPoisoned(op) => Some(op),
+            e => e.op_error().map(|e| e.op),
+        }
+    }
+
+    /// The smb url the failed operation was called on
+    pub fn url(&self) -> Option<&str> {
+        match self {
+            SmbcError::AuthenticationFailed(e) => Some(&e.url),
+            SmbcError::ProtocolNegotiationFailed(e) => Some(&e.url),
+            e => e.op_error().map(|e| e.url.as_str()),
+        }
+    }
+
+    /// The errno behind the error, if there is one
+    pub fn raw_os_error(&self) -> Option<i32> {
+        match self {
+            SmbcError::FFIError(_)
+            | SmbcError::SmbcXAttrError(_)
+            | SmbcError::ContextPoisoned(_) => None,
+            SmbcError::IoError(e) => e.raw_os_error(),
+            SmbcError::AuthenticationFailed(e) => e.source.raw_os_error(),
+            SmbcError::ProtocolNegotiationFailed(e) => e.source.raw_os_error(),
+            e => e.op_error().and_then(|e| e.source.raw_os_error()),
+        }
+    }
+
+    fn op_error(&self) -> Option<&SmbcOpError> {
+        match self {
+            SmbcError::NotFound(e)
+            | SmbcError::AccessDenied(e)
+            | SmbcError::AlreadyExists(e)
+            | SmbcError::NotEmpty(e)
+            | SmbcError::SharingViolation(e)
+            | SmbcError::ConnectionLost(e)
+            | SmbcError::Timeout(e)
+            | SmbcError::Parse(e)
+            | SmbcError::OperationFailed(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+/// A failed libsmbclient call: the operation, the smb url it was called
+/// on and the error libsmbclient gave
+pub struct SmbcOpError {
+    /// the operation (stat, open, setxattr...)
+    pub op: &'static str,
+    /// the smb url the operation was called on (without its password)
+    pub url: String,
+    /// why it failed
+    pub source: io::Error,
+}
+
+impl SmbcOpError {
+    pub fn new(op: &'static str, url: &Path, source: io::Error) -> Self {
+        SmbcOpError { op, url: redacted(url), source }
+    }
+
+    /// The message of the SmbcError holding it, which derive-error
+    /// displays through as_str (msg_embedded)
+    pub fn as_str(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SmbcOpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}: {}", self.op, self.url, self.source)
+    }
+}
+
+impl error::Error for SmbcOpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[derive(Debug)]
+/// A failed authentication: how the context tried to authenticate,
+/// where, and the error libsmbclient gave
+pub struct SmbcAuthError {
+    /// the operation that connected (stat, open, setxattr...)
+    pub op: &'static str,
+    /// the smb url the operation was called on
+    pub url: String,
+    /// the authentication method the context was configured with
+    pub method: AuthMethod,
+    /// the server authenticated against
+    pub server: String,
+    /// the share authenticated against (empty for the server itself)
+    pub share: String,
+    /// why it failed
+    pub source: io::Error,
+}
+
+impl SmbcAuthError {
+    /// The message of the SmbcError holding it, which derive-error
+    /// displays through as_str (msg_embedded)
+    pub fn as_str(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SmbcAuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: {} authentication on \\\\{}\\{} failed: {}",
+            self.op, self.url, self.method, self.server, self.share, self.source
+        )
+    }
+}
+
+impl error::Error for SmbcAuthError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+#[derive(Debug)]
+/// A failed protocol negotiation: the dialects the context offered, the
+/// server that refused them and the error libsmbclient gave
+pub struct SmbcProtocolError {
+    /// the operation that connected (stat, open, setxattr...)
+    pub op: &'static str,
+    /// the smb url the operation was called on
+    pub url: String,
+    /// the protocol range the context was configured with
+    pub range: ProtocolRange,
+    /// the server negotiated with
+    pub server: String,
+    /// why it failed
+    pub source: io::Error,
+}
+
+impl SmbcProtocolError {
+    /// The message of the SmbcError holding it, which derive-error
+    /// displays through as_str (msg_embedded)
+    pub fn as_str(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl fmt::Display for SmbcProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}: protocol negotiation with {} failed, no dialect in {} accepted: {}",
+            self.op, self.url, self.server, self.range, self.source
+        )
+    }
+}
+
+impl error::Error for SmbcProtocolError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.source)
+    }
 }
@@ -1,12 +1,40 @@
 #![allow(unsafe_code)]
 
+/// async API (the async feature)
+#[cfg(feature = "async")]
+pub mod async_smbc;
+/// context credentials
+pub mod auth;
+/// network browsing
+pub mod browse;
+/// context configuration
+pub mod builder;
 /// error handlers
 pub mod error;
+/// file, directory and share metadata
+pub mod metadata;
+/// change notification
+pub mod notify;
+/// file open options
+pub mod options;
+/// context pools
+pub mod pool;
+/// printing to print shares
+pub mod print;
+/// retrying failed calls
+pub mod retry;
 
 pub mod parser;
 /// API module
 pub mod smbc;
+/// smb url parsing
+pub mod url;
 
-pub use crate::{error::*, smbc::*};
+pub use crate::{
+    auth::*, browse::*, builder::*, error::*, metadata::*, notify::*, options::*, pool::*,
+    print::*, retry::*, smbc::*, url::*,
+};
 
+#[cfg(feature = "async")]
+pub use crate::async_smbc::*;
 pub use crate::parser::*;
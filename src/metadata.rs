@@ -0,0 +1,311 @@
+//! `metadata` describes files and directories returned by stat and listings,
+//! and the shares they are on
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::smbc::DosMode;
+use bitflags::bitflags;
+use libc::c_ulong;
+use nix::sys::stat::{Mode, SFlag};
+use rust_smbclient_sys::{stat, statvfs, timespec};
+
+#[test]
+fn test_system_time() {
+    let ts = |tv_sec, tv_nsec| timespec { tv_sec, tv_nsec };
+    assert_eq!(system_time(ts(0, 0)), UNIX_EPOCH);
+    assert_eq!(system_time(ts(1_500_000_000, 5)), UNIX_EPOCH + Duration::new(1_500_000_000, 5));
+    assert_eq!(system_time(ts(-1, 500_000_000)), UNIX_EPOCH - Duration::from_millis(500));
+}
+
+#[test]
+fn test_metadata() {
+    let mut st: stat = unsafe { std::mem::zeroed() };
+    st.st_mode = 0o100_744;
+    st.st_size = 4096;
+    st.st_ino = 42;
+    let meta = SmbcMetadata::from(st);
+    assert!(meta.is_file() && !meta.is_dir());
+    assert_eq!(meta.len(), 4096);
+    assert_eq!(meta.inode(), 42);
+    assert_eq!(meta.permissions(), Mode::from_bits_truncate(0o744));
+    assert_eq!(meta.dos_mode(), DosMode::ARCHIVE);
+    assert_eq!(meta.created(), None);
+
+    st.st_mode = 0o040_555;
+    assert_eq!(SmbcMetadata::from(st).dos_mode(), DosMode::DIRECTORY | DosMode::READONLY);
+
+    let btime = timespec { tv_sec: 60, tv_nsec: 0 };
+    let meta = SmbcMetadata::new(st, Some(btime), Some(DosMode::DIRECTORY | DosMode::HIDDEN));
+    assert_eq!(meta.created(), Some(UNIX_EPOCH + Duration::from_secs(60)));
+    assert_eq!(meta.dos_mode(), DosMode::DIRECTORY | DosMode::HIDDEN);
+}
+
+#[test]
+fn test_fs_stats() {
+    let mut st: statvfs = unsafe { std::mem::zeroed() };
+    st.f_bsize = 512;
+    st.f_frsize = 8;
+    st.f_blocks = 1000;
+    st.f_bfree = 400;
+    st.f_bavail = 300;
+    st.f_flag = (1 << 29) | (1 << 30);
+    let fs = SmbcFsStats::from(st);
+    assert_eq!(fs.block_size(), 4096);
+    assert_eq!(fs.total_bytes(), 4_096_000);
+    assert_eq!(fs.free_bytes(), 1_638_400);
+    assert_eq!(fs.available_bytes(), 1_228_800);
+    assert!(!fs.is_case_sensitive() && !fs.is_read_only() && !fs.has_unix_extensions());
+
+    // the dskattr fallback leaves f_frsize 0
+    st.f_frsize = 0;
+    st.f_flag = 1;
+    let fs = SmbcFsStats::from(st);
+    assert_eq!(fs.block_size(), 512);
+    assert!(fs.is_case_sensitive() && fs.is_read_only() && fs.has_unix_extensions());
+}
+
+#[derive(Clone, Copy)]
+/// Metadata of a file or directory, a typed view of the stat
+/// libsmbclient fills in.
+///
+/// NOTE: libsmbclient makes the mode up from the DOS attributes (see
+/// Smbc::stat), and the times are only as precise as the server keeps them.
+pub struct SmbcMetadata {
+    stat: stat,
+    /// creation time, when the call that produced this reports it
+    btime: Option<timespec>,
+    /// DOS attributes, when the call that produced this reports them
+    dos_mode: Option<DosMode>,
+}
+
+impl SmbcMetadata {
+    /// Metadata of a stat, with the creation time and DOS attributes when
+    /// the call that filled it in reports them
+    pub(crate) fn new(stat: stat, btime: Option<timespec>, dos_mode: Option<DosMode>) -> Self {
+        SmbcMetadata { stat, btime, dos_mode }
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> u64 {
+        self.stat.st_size as u64
+    }
+
+    /// Whether the size is 0
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == SFlag::S_IFDIR
+    }
+
+    pub fn is_file(&self) -> bool {
+        self.file_type() == SFlag::S_IFREG
+    }
+
+    /// Last write time
+    pub fn modified(&self) -> SystemTime {
+        system_time(self.stat.st_mtim)
+    }
+
+    /// Last access time
+    pub fn accessed(&self) -> SystemTime {
+        system_time(self.stat.st_atim)
+    }
+
+    /// Creation time.  stat does not report it (its ctime is the change
+    /// time), so this is None for metadata from stat and fstat.
+    pub fn created(&self) -> Option<SystemTime> {
+        self.btime.map(system_time)
+    }
+
+    /// Last change time (the stat ctime)
+    pub fn changed(&self) -> SystemTime {
+        system_time(self.stat.st_ctim)
+    }
+
+    /// Permission bits of the mode
+    pub fn permissions(&self) -> Mode {
+        Mode::from_bits_truncate(self.stat.st_mode as _)
+    }
+
+    /// The server's inode number (or a hash of the path when the server
+    /// has none)
+    pub fn inode(&self) -> u64 {
+        self.stat.st_ino
+    }
+
+    /// DOS attributes.  When the call that produced this metadata did not
+    /// report them they are read back from the mode the way libsmbclient
+    /// maps them: no owner write is READONLY, owner execute ARCHIVE, group
+    /// execute SYSTEM and other execute HIDDEN (each only meaningful when
+    /// the share maps that attribute, see Smbc::stat).  Directories always
+    /// have execute set, so only DIRECTORY and READONLY are read for them.
+    pub fn dos_mode(&self) -> DosMode {
+        if let Some(mode) = self.dos_mode {
+            return mode;
+        }
+        let perm = self.permissions();
+        let mut mode = DosMode::empty();
+        if !perm.contains(Mode::S_IWUSR) {
+            mode |= DosMode::READONLY;
+        }
+        if self.is_dir() {
+            return mode | DosMode::DIRECTORY;
+        }
+        if perm.contains(Mode::S_IXUSR) {
+            mode |= DosMode::ARCHIVE;
+        }
+        if perm.contains(Mode::S_IXGRP) {
+            mode |= DosMode::SYSTEM;
+        }
+        if perm.contains(Mode::S_IXOTH) {
+            mode |= DosMode::HIDDEN;
+        }
+        mode
+    }
+
+    /// The raw stat
+    pub fn as_stat(&self) -> &stat {
+        &self.stat
+    }
+
+    fn file_type(&self) -> SFlag {
+        SFlag::from_bits_truncate(self.stat.st_mode as _) & SFlag::S_IFMT
+    }
+}
+
+impl From<stat> for SmbcMetadata {
+    fn from(stat: stat) -> Self {
+        SmbcMetadata::new(stat, None, None)
+    }
+}
+
+impl fmt::Debug for SmbcMetadata {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmbcMetadata")
+            .field("len", &self.len())
+            .field("is_dir", &self.is_dir())
+            .field("permissions", &self.permissions())
+            .field("modified", &self.modified())
+            .field("inode", &self.inode())
+            .field("dos_mode", &self.dos_mode())
+            .finish()
+    }
+}
+
+/// The SystemTime of a timespec (which may be before the epoch)
+pub(crate) fn system_time(ts: timespec) -> SystemTime {
+    let since = |secs: u64| Duration::new(secs, 0);
+    let nanos = Duration::from_nanos(ts.tv_nsec as u64);
+    if ts.tv_sec >= 0 {
+        UNIX_EPOCH + since(ts.tv_sec as u64) + nanos
+    } else {
+        UNIX_EPOCH - since(ts.tv_sec.unsigned_abs()) + nanos
+    }
+}
+
+bitflags! {
+    /// Share capabilities libsmbclient reports in the statvfs f_flag
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct SmbcFsFlags : c_ulong {
+        /// The share is read-only
+        const RDONLY = 1 << 0;
+        /// The share is part of a DFS namespace
+        const DFS = 1 << 28;
+        /// Names on the share are case-insensitive
+        const CASE_INSENSITIVE = 1 << 29;
+        /// The server does not speak the CIFS unix extensions
+        const NO_UNIXCIFS = 1 << 30;
+    }
+}
+
+#[derive(Clone, Copy)]
+/// Capacity and capabilities of a share, a typed view of the statvfs
+/// libsmbclient fills in.
+///
+/// NOTE: libsmbclient puts the bytes per sector in f_bsize and the sectors
+/// per allocation unit in f_frsize (left 0 by servers that only answer the
+/// old disk attributes query), and counts blocks in allocation units.
+/// File counts and the name length are not reported.  Servers give no
+/// unicode flag: every SMB2+ share speaks unicode names.
+pub struct SmbcFsStats {
+    stat: statvfs,
+}
+
+// the statvfs field types are u32 or u64 depending on the platform
+#[allow(clippy::unnecessary_cast)]
+impl SmbcFsStats {
+    /// Size in bytes of an allocation unit, the unit of the block counts
+    pub fn block_size(&self) -> u64 {
+        self.stat.f_bsize as u64 * (self.stat.f_frsize as u64).max(1)
+    }
+
+    /// Number of allocation units on the share
+    pub fn blocks(&self) -> u64 {
+        self.stat.f_blocks as u64
+    }
+
+    /// Size of the share in bytes
+    pub fn total_bytes(&self) -> u64 {
+        self.blocks() * self.block_size()
+    }
+
+    /// Free bytes on the share
+    pub fn free_bytes(&self) -> u64 {
+        self.stat.f_bfree as u64 * self.block_size()
+    }
+
+    /// Free bytes the user may write (less than free_bytes under a quota)
+    pub fn available_bytes(&self) -> u64 {
+        self.stat.f_bavail as u64 * self.block_size()
+    }
+
+    /// Share capabilities
+    pub fn flags(&self) -> SmbcFsFlags {
+        SmbcFsFlags::from_bits_truncate(self.stat.f_flag)
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.flags().contains(SmbcFsFlags::RDONLY)
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        !self.flags().contains(SmbcFsFlags::CASE_INSENSITIVE)
+    }
+
+    pub fn is_dfs(&self) -> bool {
+        self.flags().contains(SmbcFsFlags::DFS)
+    }
+
+    /// Whether the server speaks the CIFS unix extensions
+    pub fn has_unix_extensions(&self) -> bool {
+        !self.flags().contains(SmbcFsFlags::NO_UNIXCIFS)
+    }
+
+    /// The raw statvfs
+    pub fn as_statvfs(&self) -> &statvfs {
+        &self.stat
+    }
+}
+
+impl From<statvfs> for SmbcFsStats {
+    fn from(stat: statvfs) -> Self {
+        SmbcFsStats { stat }
+    }
+}
+
+impl fmt::Debug for SmbcFsStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SmbcFsStats")
+            .field("block_size", &self.block_size())
+            .field("total_bytes", &self.total_bytes())
+            .field("free_bytes", &self.free_bytes())
+            .field("available_bytes", &self.available_bytes())
+            .field("flags", &self.flags())
+            .finish()
+    }
+}
@@ -0,0 +1,318 @@
+//! `notify` watches directories for changes with SMB change notification
+
+use std::{
+    collections::VecDeque,
+    ffi::CStr,
+    io,
+    os::raw::c_void,
+    path::{Path, PathBuf},
+    slice,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    error::{SmbcError, SmbcOpError, SmbcResult},
+    smbc::{check_neg_result, lock, SmbcHandle, URL_NAME},
+};
+use bitflags::bitflags;
+use libc::{c_int, c_uint, ETIMEDOUT};
+use percent_encoding::utf8_percent_encode;
+use rust_smbclient_sys::{
+    smbc_bool, smbc_notify_callback_action, smbc_notify_callback_fn, SMBCCTX, SMBCFILE,
+};
+
+#[test]
+fn test_change_event() {
+    let dir = Path::new("smb://files01/inbox");
+    let name = std::ffi::CString::new("2019\\q1 100%.csv").unwrap();
+    let action = |action| smbc_notify_callback_action { action, filename: name.as_ptr() };
+
+    let event = unsafe { ChangeEvent::from_action(dir, &action(1)) }.unwrap();
+    assert_eq!(event.action, ChangeAction::Added);
+    assert_eq!(event.name, "2019/q1 100%.csv");
+    assert_eq!(event.path, Path::new("smb://files01/inbox/2019/q1 100%25.csv"));
+
+    let event = unsafe { ChangeEvent::from_action(dir, &action(5)) }.unwrap();
+    assert_eq!(event.action, ChangeAction::RenamedNew);
+    assert!(matches!(
+        unsafe { ChangeEvent::from_action(dir, &action(9)) },
+        Err(SmbcError::Parse(_))
+    ));
+}
+
+#[test]
+fn test_callback_timeout() {
+    assert_eq!(callback_timeout_ms(None), 250);
+    assert_eq!(callback_timeout_ms(Some(Duration::from_secs(5))), 250);
+    assert_eq!(callback_timeout_ms(Some(Duration::from_millis(40))), 40);
+    assert_eq!(callback_timeout_ms(Some(Duration::from_millis(0))), 1);
+}
+
+/// How often libsmbclient hands control back while waiting, so that a
+/// cancel or a timeout is noticed
+const TICK: Duration = Duration::from_millis(250);
+
+bitflags! {
+    /// The kinds of change a watch reports (the completion filter)
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct NotifyFilter : u32 {
+        /// a file is added, removed or renamed
+        const FILE_NAME = 0x0001;
+        /// a directory is added, removed or renamed
+        const DIR_NAME = 0x0002;
+        /// DOS attributes change
+        const ATTRIBUTES = 0x0004;
+        /// a file size changes
+        const SIZE = 0x0008;
+        /// the last write time changes
+        const LAST_WRITE = 0x0010;
+        /// the last access time changes
+        const LAST_ACCESS = 0x0020;
+        /// the creation time changes
+        const CREATION = 0x0040;
+        /// extended attributes change
+        const EA = 0x0080;
+        /// the security descriptor changes
+        const SECURITY = 0x0100;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// What happened to a changed name
+pub enum ChangeAction {
+    /// the name was created
+    Added = 1,
+    /// the name was deleted
+    Removed = 2,
+    /// the file or directory was modified (see NotifyFilter)
+    Modified = 3,
+    /// the name was renamed away, the RenamedNew event follows
+    RenamedOld = 4,
+    /// the new name of a rename
+    RenamedNew = 5,
+}
+
+impl ChangeAction {
+    fn from_raw(action: u32) -> Option<ChangeAction> {
+        match action {
+            1 => Some(ChangeAction::Added),
+            2 => Some(ChangeAction::Removed),
+            3 => Some(ChangeAction::Modified),
+            4 => Some(ChangeAction::RenamedOld),
+            5 => Some(ChangeAction::RenamedNew),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A change in a watched directory
+pub struct ChangeEvent {
+    pub action: ChangeAction,
+    /// the changed name, relative to the watched directory ('/' separated)
+    pub name: String,
+    /// the smb url of the changed file or directory
+    pub path: PathBuf,
+}
+
+impl ChangeEvent {
+    /// The event of an action libsmbclient reported for dir
+    ///
+    /// Safety: action.filename must be a valid C string
+    unsafe fn from_action(dir: &Path, action: &smbc_notify_callback_action) -> SmbcResult<Self> {
+        let name = CStr::from_ptr(action.filename).to_string_lossy().replace('\\', "/");
+        let path = dir.join(utf8_percent_encode(&name, URL_NAME).to_string());
+        match ChangeAction::from_raw(action.action) {
+            Some(kind) => Ok(ChangeEvent { action: kind, name, path }),
+            None => {
+                let err = io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown notify action {} for {}", action.action, name),
+                );
+                Err(SmbcError::Parse(SmbcOpError::new("notify", dir, err)))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+/// Cancels a watch from another thread (see SmbcWatch::canceller)
+pub struct SmbcWatchCanceller(Arc<AtomicBool>);
+
+impl SmbcWatchCanceller {
+    /// Stop the watch.  A waiting watch notices within a quarter second
+    /// and its iterator ends.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// A watched directory, an iterator of the changes in it.
+///
+/// Each next waits until the server reports a change, the timeout passes
+/// (an Err(Timeout), the watch can go on) or the watch is cancelled (the
+/// iterator ends).  The server keeps the changes made between waits.
+///
+/// ```no_run
+/// use rust_smb::{NotifyFilter, Smbc};
+/// use std::{path::Path, time::Duration};
+///
+/// let smbc = Smbc::new_with_auth(0).unwrap();
+/// let mut watch = smbc
+///     .watch(Path::new("smb://files01/inbox"), true, NotifyFilter::FILE_NAME)
+///     .unwrap();
+/// watch.set_timeout(Some(Duration::from_secs(30)));
+/// for event in watch {
+///     match event {
+///         Ok(event) => println!("{:?} {:?}", event.action, event.path),
+///         Err(e) => eprintln!("{}", e),
+///     }
+/// }
+/// ```
+pub struct SmbcWatch {
+    /// the open directory
+    handle: Arc<SmbcHandle>,
+    notify_fn: NotifyFn,
+    recursive: bool,
+    filter: NotifyFilter,
+    timeout: Option<Duration>,
+    cancelled: Arc<AtomicBool>,
+    /// changes reported but not yet returned
+    pending: VecDeque<SmbcResult<ChangeEvent>>,
+}
+
+/// smbc_notify
+type NotifyFn = unsafe extern "C" fn(
+    c: *mut SMBCCTX,
+    dir: *mut SMBCFILE,
+    recursive: smbc_bool,
+    completion_filter: u32,
+    callback_timeout_ms: c_uint,
+    cb: smbc_notify_callback_fn,
+    private_data: *mut c_void,
+) -> c_int;
+
+/// What the notify callback fills in
+struct NotifyState<'a> {
+    dir: &'a Path,
+    cancelled: &'a AtomicBool,
+    deadline: Option<Instant>,
+    events: &'a mut VecDeque<SmbcResult<ChangeEvent>>,
+}
+
+impl SmbcWatch {
+    pub(crate) fn new(
+        handle: Arc<SmbcHandle>,
+        notify_fn: NotifyFn,
+        recursive: bool,
+        filter: NotifyFilter,
+    ) -> Self {
+        SmbcWatch {
+            handle,
+            notify_fn,
+            recursive,
+            filter,
+            timeout: None,
+            cancelled: Arc::new(AtomicBool::new(false)),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// How long next waits for a change before it returns Err(Timeout).
+    /// None (the default) waits until a change or a cancel.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// A handle that cancels this watch from another thread
+    pub fn canceller(&self) -> SmbcWatchCanceller {
+        SmbcWatchCanceller(Arc::clone(&self.cancelled))
+    }
+
+    /// Cancel the watch: the iterator ends
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// Stop watching and close the directory, returning any close error
+    pub fn close(self) -> SmbcResult<()> {
+        let ptr = lock(&self.handle.smbc, "closedir")?;
+        self.handle.close(&ptr)
+    }
+
+    /// Wait once for the server to report changes into pending
+    fn wait(&mut self) -> SmbcResult<()> {
+        let ptr = lock(&self.handle.smbc, "notify")?;
+        let mut state = NotifyState {
+            dir: &self.handle.path,
+            cancelled: &self.cancelled,
+            deadline: self.timeout.map(|t| Instant::now() + t),
+            events: &mut self.pending,
+        };
+        let (notify_fn, recursive, filter) = (self.notify_fn, self.recursive, self.filter);
+        let tick = callback_timeout_ms(self.timeout);
+        self.handle.call(&ptr, "notify", |ctx, handle| {
+            check_neg_result(unsafe {
+                notify_fn(
+                    ctx,
+                    handle,
+                    recursive as smbc_bool,
+                    filter.bits(),
+                    tick,
+                    Some(notify_callback),
+                    &mut state as *mut NotifyState as *mut c_void,
+                )
+            })
+        })?;
+        if state.events.is_empty() && !self.cancelled.load(Ordering::SeqCst) {
+            let err = io::Error::from_raw_os_error(ETIMEDOUT);
+            return Err(SmbcError::Timeout(SmbcOpError::new("notify", &self.handle.path, err)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for SmbcWatch {
+    type Item = SmbcResult<ChangeEvent>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(event) = self.pending.pop_front() {
+            return Some(event);
+        }
+        if self.cancelled.load(Ordering::SeqCst) {
+            return None;
+        }
+        match self.wait() {
+            Ok(()) => self.pending.pop_front(),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+/// The callback_timeout_ms libsmbclient returns to the callback after:
+/// the tick, or the timeout when it is shorter (0 would never return)
+fn callback_timeout_ms(timeout: Option<Duration>) -> c_uint {
+    let tick = timeout.map_or(TICK, |t| t.min(TICK));
+    tick.as_millis().max(1) as c_uint
+}
+
+/// Called by smbc_notify with the changes, or with none when the
+/// callback timeout passes.  Returning non-zero ends the wait.
+unsafe extern "C" fn notify_callback(
+    actions: *const smbc_notify_callback_action,
+    num_actions: usize,
+    private_data: *mut c_void,
+) -> c_int {
+    let state = &mut *(private_data as *mut NotifyState);
+    if num_actions > 0 {
+        for action in slice::from_raw_parts(actions, num_actions) {
+            state.events.push_back(ChangeEvent::from_action(state.dir, action));
+        }
+        return 1;
+    }
+    let expired = state.deadline.is_some_and(|d| Instant::now() >= d);
+    (expired || state.cancelled.load(Ordering::SeqCst)) as c_int
+}
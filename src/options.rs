@@ -0,0 +1,155 @@
+//! `options` chooses how files are opened, in the shape of std::fs::OpenOptions
+
+use std::{
+    io::{Error, ErrorKind},
+    path::Path,
+};
+
+use crate::{
+    error::{SmbcError, SmbcResult},
+    smbc::{Smbc, SmbcFile},
+};
+use nix::{fcntl::OFlag, sys::stat::Mode};
+
+#[test]
+fn test_open_flags() {
+    let flags = |o: &SmbcOpenOptions| o.flags().unwrap();
+    assert_eq!(flags(SmbcOpenOptions::new().read(true)), OFlag::O_RDONLY);
+    assert_eq!(
+        flags(SmbcOpenOptions::new().write(true).create(true).truncate(true)),
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC
+    );
+    assert_eq!(
+        flags(SmbcOpenOptions::new().read(true).append(true)),
+        OFlag::O_RDWR | OFlag::O_APPEND
+    );
+    assert_eq!(
+        flags(SmbcOpenOptions::new().write(true).create(true).create_new(true)),
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_EXCL
+    );
+}
+
+#[test]
+fn test_open_flags_invalid() {
+    assert!(SmbcOpenOptions::new().flags().is_err());
+    assert!(SmbcOpenOptions::new().read(true).create(true).flags().is_err());
+    assert!(SmbcOpenOptions::new().read(true).truncate(true).flags().is_err());
+    assert!(SmbcOpenOptions::new().append(true).truncate(true).flags().is_err());
+}
+
+#[derive(Debug, Clone, Default)]
+/// Options for opening a file, like std::fs::OpenOptions.
+///
+/// libsmbclient honours the access mode, O_CREAT, O_EXCL and O_TRUNC.
+/// O_APPEND only moves the file offset to the end of the file once, when
+/// it is opened: SMB has no append mode, so a file another client extends
+/// afterwards is written at the old end.  The permission mode is ignored.
+///
+/// ```no_run
+/// use rust_smb::{Smbc, SmbcOpenOptions};
+/// use std::path::Path;
+///
+/// let smbc = Smbc::new_with_auth(0).unwrap();
+/// let file = SmbcOpenOptions::new()
+///     .write(true)
+///     .create(true)
+///     .truncate(true)
+///     .open(&smbc, Path::new("smb://files01/reports/daily.csv"))
+///     .unwrap();
+/// ```
+pub struct SmbcOpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    truncate: bool,
+    create: bool,
+    create_new: bool,
+}
+
+impl SmbcOpenOptions {
+    /// Options with everything off
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Open for reading
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    /// Open for writing
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    /// Open for writing at the end of the file (implies write)
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    /// Truncate an existing file to 0 bytes (requires write)
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    /// Create the file if it does not exist (requires write or append)
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    /// Create the file, failing with AlreadyExists if it exists
+    /// (requires write or append, create and truncate are then ignored)
+    pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// Open path with these options
+    ///
+    /// @return          the open file, or an InvalidInput io error when
+    ///                  the options do not make sense together (the same
+    ///                  combinations std::fs::OpenOptions refuses).
+    ///                  Otherwise errors are as for Smbc::open.
+    pub fn open(&self, smbc: &Smbc, path: &Path) -> SmbcResult<SmbcFile> {
+        smbc.open(path, self.flags()?, Mode::empty())
+    }
+
+    /// The open(2) flags for these options
+    pub fn flags(&self) -> SmbcResult<OFlag> {
+        let mut flags = match (self.read, self.write || self.append) {
+            (true, false) => OFlag::O_RDONLY,
+            (false, true) => OFlag::O_WRONLY,
+            (true, true) => OFlag::O_RDWR,
+            (false, false) => return Err(invalid("no access mode (read, write or append) set")),
+        };
+        if self.append {
+            flags |= OFlag::O_APPEND;
+        }
+        if !self.write && !self.append && (self.truncate || self.create || self.create_new) {
+            return Err(invalid("truncate, create and create_new need write or append"));
+        }
+        if self.append && self.truncate && !self.create_new {
+            return Err(invalid("append and truncate cannot be combined"));
+        }
+        if self.create_new {
+            flags |= OFlag::O_CREAT | OFlag::O_EXCL;
+        } else {
+            if self.create {
+                flags |= OFlag::O_CREAT;
+            }
+            if self.truncate {
+                flags |= OFlag::O_TRUNC;
+            }
+        }
+        Ok(flags)
+    }
+}
+
+fn invalid(msg: &str) -> SmbcError {
+    Error::new(ErrorKind::InvalidInput, msg).into()
+}
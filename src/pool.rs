@@ -0,0 +1,415 @@
+//! `pool` keeps several Smbc contexts with one configuration, for
+//! operations in parallel
+
+use std::{
+    io::{Error, ErrorKind},
+    ops::Deref,
+    path::{Path, PathBuf},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    builder::SmbcBuilder,
+    error::{SmbcError, SmbcOpError, SmbcResult},
+    smbc::Smbc,
+};
+use libc::ETIMEDOUT;
+use log::trace;
+
+#[test]
+fn test_pool_builder() {
+    let builder = SmbcPoolBuilder::new(SmbcBuilder::new());
+    assert_eq!(builder.max_size, 4);
+    assert_eq!(builder.min_idle, 1);
+    let err = builder.clone().max_size(0).build().err().unwrap();
+    match err {
+        SmbcError::IoError(e) => {
+            assert_eq!(e.to_string(), "max_size 0: a pool needs at least one context")
+        }
+        e => panic!("unexpected {:?}", e),
+    }
+    assert!(builder.max_size(2).min_idle(3).build().is_err());
+}
+
+#[test]
+fn test_expired() {
+    let base = Instant::now();
+    let at = |secs| base + Duration::from_secs(secs);
+    let since = [at(0), at(200), at(500), at(590)];
+    let (timeout, now) = (Duration::from_secs(300), at(600));
+    assert_eq!(expired(&since, 0, timeout, now), 2);
+    assert_eq!(expired(&since, 3, timeout, now), 1);
+    assert_eq!(expired(&since, 4, timeout, now), 0);
+    assert_eq!(expired(&since, 0, timeout, at(100)), 0);
+    assert_eq!(expired(&[], 1, timeout, now), 0);
+}
+
+#[derive(Debug, Clone)]
+/// Builder for an SmbcPool
+///
+/// ```no_run
+/// use rust_smb::{SmbcBuilder, SmbcPoolBuilder};
+/// use std::{path::Path, time::Duration};
+///
+/// let pool = SmbcPoolBuilder::new(SmbcBuilder::new().workgroup("CORP"))
+///     .max_size(8)
+///     .idle_timeout(Duration::from_secs(300))
+///     .health_check(Path::new("smb://files01/reports"), Duration::from_secs(60))
+///     .build()
+///     .unwrap();
+/// let meta = pool.run(|smbc| smbc.stat(Path::new("smb://files01/reports/q1.csv"))).unwrap();
+/// ```
+pub struct SmbcPoolBuilder {
+    builder: SmbcBuilder,
+    max_size: usize,
+    min_idle: usize,
+    idle_timeout: Option<Duration>,
+    wait_timeout: Option<Duration>,
+    health_check: Option<(PathBuf, Duration)>,
+}
+
+impl SmbcPoolBuilder {
+    /// A pool of up to 4 contexts built with builder, one built up front,
+    /// idle contexts kept and get waiting as long as it takes
+    pub fn new(builder: SmbcBuilder) -> Self {
+        SmbcPoolBuilder {
+            builder,
+            max_size: 4,
+            min_idle: 1,
+            idle_timeout: None,
+            wait_timeout: None,
+            health_check: None,
+        }
+    }
+
+    /// The most contexts the pool has at once (leased and idle)
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = size;
+        self
+    }
+
+    /// Contexts built by build, kept however long they idle and built
+    /// again when the pool has fewer (after failed health checks, say)
+    pub fn min_idle(mut self, count: usize) -> Self {
+        self.min_idle = count;
+        self
+    }
+
+    /// Free contexts (and their server connections) idle for longer.  A
+    /// thread of the pool looks for them every half timeout, so they are
+    /// freed even when the pool is not used.
+    pub fn idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = Some(timeout);
+        self
+    }
+
+    /// How long get waits for a context when the pool is at max_size,
+    /// before it returns Err(Timeout)
+    pub fn wait_timeout(mut self, timeout: Duration) -> Self {
+        self.wait_timeout = Some(timeout);
+        self
+    }
+
+    /// Check a context that idled for at least idle_for before leasing
+    /// it, by authenticating to url (see Smbc::authenticate).  A context
+    /// that fails the check is freed.  Poisoned contexts are always
+    /// freed.
+    pub fn health_check(mut self, url: &Path, idle_for: Duration) -> Self {
+        self.health_check = Some((url.to_path_buf(), idle_for));
+        self
+    }
+
+    /// Build the pool and its min_idle contexts
+    pub fn build(&self) -> SmbcResult<SmbcPool> {
+        if self.max_size == 0 || self.min_idle > self.max_size {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                match self.max_size {
+                    0 => "max_size 0: a pool needs at least one context".to_string(),
+                    max => format!("min_idle {} is more than max_size {}", self.min_idle, max),
+                },
+            )
+            .into());
+        }
+        let mut idle = Vec::with_capacity(self.max_size);
+        for _ in 0..self.min_idle {
+            idle.push(IdleSmbc { smbc: self.builder.build()?, since: Instant::now() });
+        }
+        let size = idle.len();
+        let inner = Arc::new(PoolInner {
+            config: self.clone(),
+            state: Mutex::new(PoolState { idle, size }),
+            released: Condvar::new(),
+        });
+        if let Some(timeout) = self.idle_timeout {
+            spawn_reaper(&inner, (timeout / 2).max(Duration::from_millis(100)))?;
+        }
+        Ok(SmbcPool { inner })
+    }
+}
+
+#[derive(Clone)]
+/// A pool of Smbc contexts with one configuration.
+///
+/// Each context serializes the calls made on it, so threads get
+/// parallelism by leasing contexts of their own: get one with get (or
+/// let run pick one) and it returns to the pool when the lease is
+/// dropped.  Files and directories stay pinned to the context that opened
+/// them, and keep it alive after it is returned or freed; the pool
+/// prefers leasing contexts with nothing open, so that those handles
+/// are not waited on.
+pub struct SmbcPool {
+    inner: Arc<PoolInner>,
+}
+
+struct PoolInner {
+    config: SmbcPoolBuilder,
+    state: Mutex<PoolState>,
+    /// notified when a context is returned or freed
+    released: Condvar,
+}
+
+struct PoolState {
+    /// the idle contexts, the most recently returned last
+    idle: Vec<IdleSmbc>,
+    /// contexts in existence or being built, leased and idle
+    size: usize,
+}
+
+struct IdleSmbc {
+    smbc: Smbc,
+    /// when it was returned
+    since: Instant,
+}
+
+/// A context leased from an SmbcPool, returned when dropped
+pub struct SmbcLease {
+    pool: Arc<PoolInner>,
+    /// None once returned or discarded
+    smbc: Option<Smbc>,
+}
+
+impl SmbcPool {
+    /// Lease a context: an idle one when there is one that passes the
+    /// health check, a new one while the pool is under max_size,
+    /// otherwise the first one returned.
+    ///
+    /// @return          The lease, or the error building a new context,
+    ///                  or Timeout when the wait_timeout passes first.
+    pub fn get(&self) -> SmbcResult<SmbcLease> {
+        let deadline = self.inner.config.wait_timeout.map(|t| Instant::now() + t);
+        self.inner.maintain();
+        loop {
+            let smbc = match self.inner.take(deadline)? {
+                Some(IdleSmbc { smbc, since }) => match self.inner.is_healthy(&smbc, since) {
+                    true => smbc,
+                    false => {
+                        self.inner.free(smbc);
+                        continue;
+                    }
+                },
+                None => match self.inner.config.builder.build() {
+                    Ok(smbc) => smbc,
+                    Err(e) => {
+                        self.inner.free_slot();
+                        return Err(e);
+                    }
+                },
+            };
+            return Ok(SmbcLease { pool: Arc::clone(&self.inner), smbc: Some(smbc) });
+        }
+    }
+
+    /// Run f on a leased context, returning it to the pool afterwards
+    pub fn run<T>(&self, f: impl FnOnce(&Smbc) -> SmbcResult<T>) -> SmbcResult<T> {
+        let smbc = self.get()?;
+        f(&smbc)
+    }
+
+    /// The contexts of the pool, leased and idle
+    pub fn size(&self) -> usize {
+        self.inner.lock().size
+    }
+
+    /// The idle contexts of the pool
+    pub fn idle(&self) -> usize {
+        self.inner.lock().idle.len()
+    }
+}
+
+impl PoolInner {
+    fn lock(&self) -> MutexGuard<'_, PoolState> {
+        // the state is consistent between statements, a panic cannot
+        // leave it half updated
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// An idle context, or None when there is room for a new one (which
+    /// is counted in size already)
+    fn take(&self, deadline: Option<Instant>) -> SmbcResult<Option<IdleSmbc>> {
+        let mut state = self.lock();
+        loop {
+            // the newest idle context without open files or directories,
+            // else room for a new one, else any idle context
+            let unused = state.idle.iter().rposition(|i| Arc::strong_count(&i.smbc.context) == 1);
+            if let Some(i) = unused {
+                return Ok(Some(state.idle.remove(i)));
+            }
+            if state.size < self.config.max_size {
+                state.size += 1;
+                return Ok(None);
+            }
+            if let Some(idle) = state.idle.pop() {
+                return Ok(Some(idle));
+            }
+            state = match deadline {
+                None => self.released.wait(state).unwrap_or_else(|e| e.into_inner()),
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        let err = Error::from_raw_os_error(ETIMEDOUT);
+                        return Err(SmbcError::Timeout(SmbcOpError::new(
+                            "lease",
+                            Path::new(""),
+                            err,
+                        )));
+                    }
+                    self.released
+                        .wait_timeout(state, deadline - now)
+                        .unwrap_or_else(|e| e.into_inner())
+                        .0
+                }
+            };
+        }
+    }
+
+    /// Whether a context returned at since may be leased again
+    fn is_healthy(&self, smbc: &Smbc, since: Instant) -> bool {
+        if smbc.is_poisoned() {
+            trace!(target: "smbc", "pool: freeing a poisoned context");
+            return false;
+        }
+        match &self.config.health_check {
+            Some((url, idle_for)) if since.elapsed() >= *idle_for => match smbc.authenticate(url) {
+                Ok(()) => true,
+                Err(e) => {
+                    trace!(target: "smbc", "pool: freeing a context that failed its health check: {}", e);
+                    false
+                }
+            },
+            _ => true,
+        }
+    }
+
+    /// Return a leased context, freeing the ones idle for longer than
+    /// idle_timeout
+    fn put(&self, smbc: Smbc) {
+        if smbc.is_poisoned() {
+            return self.free(smbc);
+        }
+        let mut state = self.lock();
+        state.idle.push(IdleSmbc { smbc, since: Instant::now() });
+        let expired = self.expire(&mut state);
+        drop(state);
+        self.released.notify_all();
+        // contexts log off their servers when freed, not under the lock
+        drop(expired);
+    }
+
+    /// Free the contexts idle for longer than idle_timeout, and build new
+    /// ones while the pool has fewer than min_idle
+    fn maintain(&self) {
+        let mut state = self.lock();
+        let expired = self.expire(&mut state);
+        let missing = self.config.min_idle.saturating_sub(state.size);
+        state.size += missing;
+        drop(state);
+        drop(expired);
+        for _ in 0..missing {
+            match self.config.builder.build() {
+                Ok(smbc) => {
+                    self.lock().idle.push(IdleSmbc { smbc, since: Instant::now() });
+                    self.released.notify_all();
+                }
+                Err(e) => {
+                    trace!(target: "smbc", "pool: building an idle context failed: {}", e);
+                    self.free_slot();
+                }
+            }
+        }
+    }
+
+    /// Take the contexts idle for longer than idle_timeout out of the
+    /// pool, keeping min_idle, for the caller to free once it unlocks
+    fn expire(&self, state: &mut PoolState) -> Vec<IdleSmbc> {
+        let timeout = match self.config.idle_timeout {
+            Some(timeout) => timeout,
+            None => return vec![],
+        };
+        let since: Vec<Instant> = state.idle.iter().map(|i| i.since).collect();
+        let n = expired(&since, self.config.min_idle, timeout, Instant::now());
+        state.size -= n;
+        state.idle.drain(..n).collect()
+    }
+
+    /// Free a context, making room for a new one
+    fn free(&self, smbc: Smbc) {
+        self.free_slot();
+        drop(smbc);
+    }
+
+    /// Make room for a new context
+    fn free_slot(&self) {
+        self.lock().size -= 1;
+        self.released.notify_all();
+    }
+}
+
+/// How many idle contexts, returned at since (oldest first), have idled
+/// for timeout at now, leaving min_idle of them
+fn expired(since: &[Instant], min_idle: usize, timeout: Duration, now: Instant) -> usize {
+    let past = since.iter().take_while(|&&since| now.duration_since(since) >= timeout).count();
+    past.min(since.len().saturating_sub(min_idle))
+}
+
+/// Start the thread that frees the expired contexts of pool every period,
+/// until the pool is dropped
+fn spawn_reaper(pool: &Arc<PoolInner>, period: Duration) -> SmbcResult<()> {
+    let pool = Arc::downgrade(pool);
+    thread::Builder::new().name("smbc-pool".to_string()).spawn(move || loop {
+        thread::sleep(period);
+        match pool.upgrade() {
+            Some(pool) => pool.maintain(),
+            None => return,
+        }
+    })?;
+    Ok(())
+}
+
+impl SmbcLease {
+    /// Free the context instead of returning it to the pool, when it is
+    /// known to be broken
+    pub fn discard(mut self) {
+        if let Some(smbc) = self.smbc.take() {
+            self.pool.free(smbc);
+        }
+    }
+}
+
+impl Deref for SmbcLease {
+    type Target = Smbc;
+
+    fn deref(&self) -> &Smbc {
+        self.smbc.as_ref().expect("the context is only taken on discard or drop")
+    }
+}
+
+impl Drop for SmbcLease {
+    fn drop(&mut self) {
+        if let Some(smbc) = self.smbc.take() {
+            self.pool.put(smbc);
+        }
+    }
+}
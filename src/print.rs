@@ -0,0 +1,184 @@
+//! `print` sends files to print shares and manages their print jobs
+
+use std::{cell::RefCell, ffi::CString, os::unix::ffi::OsStrExt, path::Path, time::SystemTime};
+
+use crate::{
+    error::SmbcResult,
+    metadata::system_time,
+    smbc::{check_mut_ptr, check_neg_result, lock, Smbc, SmbcFile},
+};
+use libc::{c_char, c_int};
+use rust_smbclient_sys::{print_job_info, timespec};
+
+#[test]
+fn test_print_job() {
+    let mut info: print_job_info = unsafe { std::mem::zeroed() };
+    info.id = 17;
+    info.priority = 1;
+    info.size = 52_430;
+    info.t = 1_546_300_800;
+    for (c, b) in info.user.iter_mut().zip(b"alice\0") {
+        *c = *b as c_char;
+    }
+    // a name filling the whole buffer has no terminating nul
+    for c in info.name.iter_mut() {
+        *c = b'x' as c_char;
+    }
+    let job = SmbcPrintJob::from(&info);
+    assert_eq!(job.id, 17);
+    assert_eq!(job.size, 52_430);
+    assert_eq!(job.user, "alice");
+    assert_eq!(job.name, "x".repeat(128));
+    assert_eq!(
+        job.submitted,
+        std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_546_300_800)
+    );
+}
+
+thread_local! {
+    /// The jobs list_print_jobs is collecting.  libsmbclient calls the
+    /// callback on the calling thread without any user data, while the
+    /// context is locked.
+    static PRINT_JOBS: RefCell<Vec<SmbcPrintJob>> = const { RefCell::new(Vec::new()) };
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A job in the queue of a print share
+pub struct SmbcPrintJob {
+    /// the job id, for cancel_print_job
+    pub id: u16,
+    pub priority: u16,
+    /// bytes spooled
+    pub size: usize,
+    /// who submitted the job
+    pub user: String,
+    /// the document name
+    pub name: String,
+    /// when the job was submitted
+    pub submitted: SystemTime,
+}
+
+impl From<&print_job_info> for SmbcPrintJob {
+    fn from(info: &print_job_info) -> Self {
+        SmbcPrintJob {
+            id: info.id,
+            priority: info.priority,
+            size: info.size,
+            user: fixed_str(&info.user),
+            name: fixed_str(&info.name),
+            submitted: system_time(timespec { tv_sec: info.t, tv_nsec: 0 }),
+        }
+    }
+}
+
+/// The string in a fixed size, nul padded buffer (which may not have a
+/// terminating nul)
+fn fixed_str(buf: &[c_char]) -> String {
+    let bytes: Vec<u8> = buf.iter().take_while(|&&c| c != 0).map(|&c| c as u8).collect();
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Called by list_print_jobs once for each job in the queue
+unsafe extern "C" fn print_job_callback(info: *mut print_job_info) {
+    if let Some(info) = info.as_ref() {
+        PRINT_JOBS.with(|jobs| jobs.borrow_mut().push(SmbcPrintJob::from(info)));
+    }
+}
+
+impl Smbc {
+    /// Print a file on a print share: copy it into a new print job
+    ///
+    /// @param file      The smb url of the file to print
+    ///
+    /// @param printer   The smb url of the print share
+    ///
+    /// @return          Nothing on success, Error with errno set:
+    ///                  - EINVAL  an url would not parse
+    ///                  - ENOENT  the file does not exist
+    ///                  - EACCES  the file cannot be read or the printer
+    ///                    cannot be printed to
+    ///
+    /// @note: to print local data, write it to open_print_job instead
+    pub fn print_file(&self, file: &Path, printer: &Path) -> SmbcResult<()> {
+        let cfile = CString::new(file.as_os_str().as_bytes())?;
+        let cprinter = CString::new(printer.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "print_file")?;
+        ptr.call("print_file", file, |ctx| {
+            check_neg_result(unsafe {
+                (self.print_file_fn)(ctx, cfile.as_ptr(), ctx, cprinter.as_ptr())
+            })
+        })?;
+        Ok(())
+    }
+
+    /// Open a print job on a print share.  The data written to the
+    /// returned file is printed once it is closed.
+    ///
+    /// ```no_run
+    /// use rust_smb::Smbc;
+    /// use std::{fs::File, io, path::Path};
+    ///
+    /// let smbc = Smbc::new_with_auth(0).unwrap();
+    /// let mut job = smbc.open_print_job(Path::new("smb://print01/laser2/invoice.pdf")).unwrap();
+    /// io::copy(&mut File::open("invoice.pdf").unwrap(), &mut job).unwrap();
+    /// job.close().unwrap();
+    /// ```
+    ///
+    /// @param path      The smb url of the print share, optionally
+    ///                  followed by the document name
+    ///
+    /// @return          The job, open for writing only.  Error with
+    ///                  errno set:
+    ///                  - EINVAL  the url would not parse
+    ///                  - ENODEV  the share does not exist
+    ///                  - EACCES  the printer cannot be printed to
+    pub fn open_print_job(&self, path: &Path) -> SmbcResult<SmbcFile> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "open_print_job")?;
+        let handle = ptr.call("open_print_job", path, |ctx| {
+            check_mut_ptr(unsafe { (self.open_print_job_fn)(ctx, cpath.as_ptr()) })
+        })?;
+        Ok(self.file(handle, path))
+    }
+
+    /// The jobs in the queue of a print share
+    ///
+    /// @param printer   The smb url of the print share
+    ///
+    /// @note: libsmbclient lists print queues with the SMB1 remote
+    ///        administration protocol, servers that only speak SMB2+
+    ///        cannot be listed.
+    pub fn list_print_jobs(&self, printer: &Path) -> SmbcResult<Vec<SmbcPrintJob>> {
+        let cprinter = CString::new(printer.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "list_print_jobs")?;
+        PRINT_JOBS.with(|jobs| jobs.borrow_mut().clear());
+        let listed = ptr.call("list_print_jobs", printer, |ctx| {
+            check_neg_result(unsafe {
+                (self.list_print_jobs_fn)(ctx, cprinter.as_ptr(), Some(print_job_callback))
+            })
+        });
+        let jobs = PRINT_JOBS.with(|jobs| jobs.take());
+        listed?;
+        Ok(jobs)
+    }
+
+    /// Cancel a print job (see list_print_jobs for what this needs)
+    ///
+    /// @param printer   The smb url of the print share
+    ///
+    /// @param id        The id of the job
+    ///
+    /// @return          Nothing on success, Error with errno set:
+    ///                  - EINVAL  there is no job id in the queue
+    ///                  - EACCES  the job belongs to someone else
+    pub fn cancel_print_job(&self, printer: &Path, id: u16) -> SmbcResult<()> {
+        let cprinter = CString::new(printer.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "cancel_print_job")?;
+        ptr.call("cancel_print_job", printer, |ctx| {
+            check_neg_result(unsafe {
+                (self.unlink_print_job_fn)(ctx, cprinter.as_ptr(), id as c_int)
+            })
+        })?;
+        Ok(())
+    }
+}
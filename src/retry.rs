@@ -0,0 +1,233 @@
+//! `retry` makes calls again when they fail with transient errors
+
+use std::{thread, time::Duration};
+
+use crate::error::{SmbcError, SmbcResult};
+use bitflags::bitflags;
+use log::trace;
+
+#[test]
+fn test_delay() {
+    let policy = RetryPolicy::new().backoff(Duration::from_millis(100), Duration::from_secs(1));
+    assert_eq!(policy.delay(0), Duration::from_millis(100));
+    assert_eq!(policy.delay(1), Duration::from_millis(200));
+    assert_eq!(policy.delay(3), Duration::from_millis(800));
+    assert_eq!(policy.delay(4), Duration::from_secs(1));
+    assert_eq!(policy.delay(200), Duration::from_secs(1));
+}
+
+#[test]
+fn test_retryable() {
+    use crate::{
+        auth::{AuthAttempt, AuthMethod, SmbcAuth, SmbcCredentials},
+        builder::{ProtocolRange, SmbProtocol},
+    };
+    use libc::{EACCES, EBUSY, ECONNRESET, ENOTSUP, ETIMEDOUT};
+    use std::{io::Error, path::Path, sync::Arc};
+    let err = |errno| {
+        SmbcError::from_errno("stat", Path::new("smb://files01"), Error::from_raw_os_error(errno))
+    };
+    let policy = RetryPolicy::new();
+    assert!(policy.is_retryable(&err(ECONNRESET)));
+    assert!(policy.is_retryable(&err(ETIMEDOUT)));
+    assert!(!policy.is_retryable(&err(EBUSY)));
+    assert!(!policy.is_retryable(&err(EACCES)));
+    assert!(!policy.is_retryable(&SmbcError::ContextPoisoned("stat")));
+    assert!(!policy.is_retryable(&SmbcError::IoError(Error::from_raw_os_error(ECONNRESET))));
+    assert!(policy.clone().retry_on(RetryOn::SHARING_VIOLATION).is_retryable(&err(EBUSY)));
+
+    // a refused negotiation is not retried, a reset while connecting is
+    let url = Path::new("smb://files01/reports");
+    let mut auth = SmbcAuth::new(Arc::new(SmbcCredentials::guest()), AuthMethod::default());
+    auth.protocols = Some(ProtocolRange::only(SmbProtocol::SMB3_11));
+    auth.last_attempt = Some(AuthAttempt {
+        server: "files01".to_string(),
+        share: "reports".to_string(),
+        workgroup: "WORKGROUP".to_string(),
+        username: "guest".to_string(),
+    });
+    let refused = auth.failure("stat", url, Error::from_raw_os_error(ENOTSUP));
+    assert!(matches!(refused, SmbcError::ProtocolNegotiationFailed(_)));
+    assert!(!policy.is_retryable(&refused));
+    assert!(policy.is_retryable(&auth.failure("stat", url, Error::from_raw_os_error(ECONNRESET))));
+}
+
+#[test]
+fn test_retry() {
+    use std::{io::Error, path::Path};
+    let policy = RetryPolicy::new().max_attempts(3).backoff(Duration::ZERO, Duration::ZERO);
+    fn lost<T>() -> SmbcResult<T> {
+        let err = Error::from_raw_os_error(libc::ECONNRESET);
+        Err(SmbcError::from_errno("stat", Path::new("smb://files01"), err))
+    }
+
+    let (mut calls, mut recoveries) = (0, 0);
+    let res: SmbcResult<()> = retry(
+        Some(&policy),
+        || {
+            recoveries += 1;
+            Ok(())
+        },
+        || {
+            calls += 1;
+            lost()
+        },
+    );
+    assert!(res.is_err());
+    assert_eq!((calls, recoveries), (3, 2));
+
+    let mut calls = 0;
+    let res = retry(
+        Some(&policy),
+        || Ok(()),
+        || {
+            calls += 1;
+            if calls < 2 {
+                lost()
+            } else {
+                Ok(calls)
+            }
+        },
+    );
+    assert_eq!(res.unwrap(), 2);
+
+    let mut calls = 0;
+    let _ = retry(
+        None,
+        || Ok(()),
+        || -> SmbcResult<()> {
+            calls += 1;
+            lost()
+        },
+    );
+    assert_eq!(calls, 1);
+}
+
+bitflags! {
+    /// The kinds of failure a RetryPolicy retries
+    #[derive(Copy, Clone, Eq, PartialEq, Debug)]
+    pub struct RetryOn : u32 {
+        /// the connection could not be made or was dropped
+        /// (SmbcError::ConnectionLost)
+        const CONNECTION_LOST = 0x1;
+        /// the server did not answer in time (SmbcError::Timeout)
+        const TIMEOUT = 0x2;
+        /// another open of the file conflicts
+        /// (SmbcError::SharingViolation)
+        const SHARING_VIOLATION = 0x4;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// When and how often a context makes a failed call again (see
+/// SmbcBuilder::retry_policy).
+///
+/// Only calls that are safe to repeat are retried: stat, statvfs,
+/// opendir, getxattr, listxattr and the reads, seeks and fstats of files
+/// opened read-only.  Before each retry the context purges its cached
+/// server connections, so the retry connects again, and a read-only file
+/// is opened again and seeked back to its offset.
+///
+/// ```no_run
+/// use rust_smb::{RetryOn, RetryPolicy, SmbcBuilder};
+/// use std::time::Duration;
+///
+/// let smbc = SmbcBuilder::new()
+///     .retry_policy(
+///         RetryPolicy::new()
+///             .max_attempts(5)
+///             .backoff(Duration::from_millis(200), Duration::from_secs(10))
+///             .retry_on(RetryOn::CONNECTION_LOST | RetryOn::TIMEOUT),
+///     )
+///     .build()
+///     .unwrap();
+/// ```
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    retry_on: RetryOn,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retry_on: RetryOn::CONNECTION_LOST | RetryOn::TIMEOUT,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// 3 attempts, waiting 100ms then doubling up to 5s, on lost
+    /// connections and timeouts
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Attempts in all, the first call included (1 never retries)
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = attempts;
+        self
+    }
+
+    /// Wait initial before the first retry, doubling for each retry after
+    /// up to max
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+        self
+    }
+
+    /// The kinds of failure retried
+    pub fn retry_on(mut self, kinds: RetryOn) -> Self {
+        self.retry_on = kinds;
+        self
+    }
+
+    /// Whether err is a failure this policy retries, by its class
+    pub fn is_retryable(&self, err: &SmbcError) -> bool {
+        let kind = match err {
+            SmbcError::ConnectionLost(_) => RetryOn::CONNECTION_LOST,
+            SmbcError::Timeout(_) => RetryOn::TIMEOUT,
+            SmbcError::SharingViolation(_) => RetryOn::SHARING_VIOLATION,
+            _ => return false,
+        };
+        self.retry_on.intersects(kind)
+    }
+
+    /// The wait before retry number retry (from 0)
+    pub fn delay(&self, retry: u32) -> Duration {
+        let factor = 1u32.checked_shl(retry).unwrap_or(u32::MAX);
+        self.initial_backoff.saturating_mul(factor).min(self.max_backoff)
+    }
+}
+
+/// Call f, and while it fails with an error policy retries (and attempts
+/// are left) wait, recover and call it again.  A failed recover counts as
+/// a failed attempt.  No policy calls f once.
+pub(crate) fn retry<T>(
+    policy: Option<&RetryPolicy>,
+    mut recover: impl FnMut() -> SmbcResult<()>,
+    mut f: impl FnMut() -> SmbcResult<T>,
+) -> SmbcResult<T> {
+    let mut res = f();
+    let policy = match policy {
+        Some(policy) => policy,
+        None => return res,
+    };
+    let mut retries = 0;
+    while let Err(e) = &res {
+        if retries + 1 >= policy.max_attempts || !policy.is_retryable(e) {
+            break;
+        }
+        let delay = policy.delay(retries);
+        trace!(target: "smbc", "{}, retrying in {:?}", e, delay);
+        thread::sleep(delay);
+        retries += 1;
+        res = recover().and_then(|()| f());
+    }
+    res
+}
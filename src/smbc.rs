@@ -3,23 +3,37 @@
 #![allow(unused_parens, clippy::tabs_in_doc_comments)]
 
 use std::{
-    ffi::{CStr, CString},
+    borrow::Cow,
+    collections::VecDeque,
+    ffi::{CStr, CString, OsStr, OsString},
     fmt,
     io::{self, Error, ErrorKind, Read, Result as IoResult, Seek, SeekFrom, Write},
-    mem::zeroed,
-    os::{raw::c_void, unix::ffi::OsStrExt},
-    panic,
+    mem::{offset_of, size_of, zeroed},
+    os::{
+        raw::c_void,
+        unix::ffi::{OsStrExt, OsStringExt},
+    },
     path::{Path, PathBuf},
     ptr,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
 };
 
 use crate::{
-    error::{SmbcError, SmbcResult},
+    auth::{AuthAttempt, AuthMethod, CredentialProvider, SmbcAuth, SmbcCredentials},
+    builder::{EncryptionLevel, ProtocolRange, SmbcBuilder, SmbcLogTarget},
+    error::{SmbcError, SmbcOpError, SmbcResult},
+    metadata::{SmbcFsStats, SmbcMetadata},
+    notify::{NotifyFilter, SmbcWatch},
     parser::*,
+    retry::{retry, RetryPolicy},
+    url::SmbUrl,
 };
 use chrono::*;
-use libc::{c_char, c_int, mode_t, off_t, strncpy, EINVAL};
+use libc::{c_char, c_int, c_uint, mode_t, off_t, EBADF, EINVAL, ENOENT};
+use nix::errno::Errno;
 pub use nix::{fcntl::OFlag, sys::stat::Mode};
 use rust_smbclient_sys::*;
 
@@ -28,6 +42,218 @@ use lazy_static::*;
 use log::{error, trace};
 use percent_encoding::*;
 
+#[test]
+fn test_decode_dirents() {
+    fn record(buf: &mut Vec<u8>, smbc_type: u32, name: &str, comment: &str) {
+        let header = offset_of!(smbc_dirent, name);
+        let dirlen = size_of::<smbc_dirent>() + name.len() + comment.len() + 1;
+        let mut rec = vec![0u8; dirlen];
+        let mut put = |off: usize, v: usize| {
+            rec[off..off + 4].copy_from_slice(&(v as u32).to_ne_bytes());
+        };
+        put(offset_of!(smbc_dirent, smbc_type), smbc_type as usize);
+        put(offset_of!(smbc_dirent, dirlen), dirlen);
+        put(offset_of!(smbc_dirent, commentlen), comment.len());
+        put(offset_of!(smbc_dirent, namelen), name.len());
+        rec[header..header + name.len()].copy_from_slice(name.as_bytes());
+        let at = header + name.len() + 1;
+        rec[at..at + comment.len()].copy_from_slice(comment.as_bytes());
+        buf.extend_from_slice(&rec);
+    }
+    let mut buf = vec![];
+    record(&mut buf, 7, "reports", "");
+    record(&mut buf, 8, "2019 q1.csv", "");
+    record(&mut buf, 3, "public", "shared files");
+    let entries = decode_dirents(&buf, false).unwrap();
+    assert_eq!(entries.len(), 3);
+    assert_eq!(entries[0].s_type, SmbcType::DIR);
+    assert_eq!(entries[0].path, Path::new("reports"));
+    assert_eq!(entries[1].s_type, SmbcType::FILE);
+    assert_eq!(entries[1].path, Path::new("2019 q1.csv"));
+    assert_eq!(entries[2].s_type, SmbcType::FILESHARE);
+    assert_eq!(entries[2].comment, "shared files");
+    assert!(decode_dirents(&[], false).unwrap().is_empty());
+
+    // a record cut short, or claiming more than is left
+    assert!(decode_dirents(&buf[..buf.len() - 3], false).is_err());
+    let mut bad = buf.clone();
+    bad[offset_of!(smbc_dirent, namelen)] = 200;
+    assert!(decode_dirents(&bad, false).is_err());
+}
+
+#[test]
+fn test_entry_name() {
+    assert_eq!(entry_name(b"100%41.txt", false), Path::new("100%41.txt"));
+    assert_eq!(entry_name(b"100%2541.txt", true), Path::new("100%41.txt"));
+    assert_eq!(entry_name(b"q1%20report", true), Path::new("q1 report"));
+
+    // names that are not UTF-8 keep their bytes
+    let latin1 = entry_name(b"caf\xe9.txt", false);
+    assert_eq!(latin1.as_os_str().as_bytes(), b"caf\xe9.txt");
+    assert_eq!(entry_name(b"caf%E9.txt", true), latin1);
+    let entry = SmbcDirEntry { s_type: SmbcType::FILE, comment: String::new(), path: latin1 };
+    assert_eq!(entry.name_bytes(), b"caf\xe9.txt");
+    assert_eq!(entry.display_name(), "caf\u{fffd}.txt");
+}
+
+#[test]
+fn test_entry_url() {
+    let dir = Path::new("smb://files01/archive/");
+    let url = entry_url(dir, Path::new(OsStr::from_bytes(b"caf\xe9 100%.txt")));
+    assert_eq!(url, Path::new("smb://files01/archive/caf%E9 100%25.txt"));
+    let url = entry_url(Path::new("smb://files01/archive"), Path::new("q1 report"));
+    assert_eq!(url, Path::new("smb://files01/archive/q1 report"));
+}
+
+#[test]
+fn test_lost_file_reopens() {
+    use std::sync::atomic::AtomicI64;
+    // a fake libsmbclient file: its offset, and how often it was opened
+    static OFFSET: AtomicI64 = AtomicI64::new(0);
+    static OPENS: AtomicU64 = AtomicU64::new(0);
+    unsafe extern "C" fn open(
+        _: *mut SMBCCTX,
+        _: *const c_char,
+        _: c_int,
+        _: mode_t,
+    ) -> *mut SMBCFILE {
+        OPENS.fetch_add(1, Ordering::SeqCst);
+        OFFSET.store(0, Ordering::SeqCst);
+        ptr::NonNull::dangling().as_ptr()
+    }
+    unsafe extern "C" fn lseek(
+        _: *mut SMBCCTX,
+        _: *mut SMBCFILE,
+        off: off_t,
+        whence: c_int,
+    ) -> off_t {
+        match whence as u32 {
+            SEEK_CUR => OFFSET.fetch_add(off, Ordering::SeqCst) + off,
+            _ => {
+                OFFSET.store(off, Ordering::SeqCst);
+                off
+            }
+        }
+    }
+    unsafe extern "C" fn read(
+        _: *mut SMBCCTX,
+        _: *mut SMBCFILE,
+        _: *mut c_void,
+        n: usize,
+    ) -> isize {
+        OFFSET.fetch_add(n as off_t, Ordering::SeqCst);
+        n as isize
+    }
+    unsafe extern "C" fn write(
+        _: *mut SMBCCTX,
+        _: *mut SMBCFILE,
+        _: *const c_void,
+        _: usize,
+    ) -> isize {
+        -1
+    }
+    unsafe extern "C" fn fstat(_: *mut SMBCCTX, _: *mut SMBCFILE, _: *mut stat) -> c_int {
+        0
+    }
+    unsafe extern "C" fn fstatvfs(_: *mut SMBCCTX, _: *mut SMBCFILE, _: *mut statvfs) -> c_int {
+        0
+    }
+    unsafe extern "C" fn ftruncate(_: *mut SMBCCTX, _: *mut SMBCFILE, _: off_t) -> c_int {
+        -1
+    }
+    unsafe extern "C" fn close(_: *mut SMBCCTX, _: *mut SMBCFILE) -> c_int {
+        0
+    }
+
+    let auth = SmbcAuth::new(Arc::new(SmbcCredentials::guest()), AuthMethod::default());
+    let smbc = Arc::new(Mutex::new(SmbcPtr(ptr::null_mut(), Box::into_raw(Box::new(auth)))));
+    let path = Path::new("smb://files01/reports/q1.csv");
+    let mut file = SmbcFile {
+        handle: SmbcHandle::new(&smbc, ptr::null_mut(), path, close, "close"),
+        reopen_flags: Some(OFlag::O_RDONLY),
+        fstat_fn: fstat,
+        fstatvfs_fn: fstatvfs,
+        ftruncate_fn: ftruncate,
+        lseek_fn: lseek,
+        open_fn: open,
+        read_fn: read,
+        write_fn: write,
+    };
+    // lost at offset 10: seeking from there moves the offset to reopen at
+    *file.handle.reopen_at.lock().unwrap() = Some(10);
+    assert_eq!(file.seek(SeekFrom::Current(5)).unwrap(), 15);
+    assert!(file.seek(SeekFrom::Current(-20)).is_err());
+    assert_eq!(OPENS.load(Ordering::SeqCst), 0);
+
+    // read_at opens it again at that offset, and leaves it there
+    let mut buf = [0u8; 4];
+    assert_eq!(file.read_at(&mut buf, 2).unwrap(), 4);
+    assert_eq!(OPENS.load(Ordering::SeqCst), 1);
+    assert!(file.handle.reopen_at.lock().unwrap().is_none());
+    assert_eq!(file.lseek(0, SEEK_CUR as c_int).unwrap(), 15);
+    assert_eq!(file.read(&mut buf).unwrap(), 4);
+    assert_eq!(OFFSET.load(Ordering::SeqCst), 19);
+}
+
+#[test]
+fn test_dir_iter_ends_on_error() {
+    unsafe extern "C" fn readdir(_: *mut SMBCCTX, _: *mut SMBCFILE) -> *mut smbc_dirent {
+        // fail with EBADF, as a dead handle does
+        libc::close(-1);
+        ptr::null_mut()
+    }
+    unsafe extern "C" fn lseekdir(_: *mut SMBCCTX, _: *mut SMBCFILE, _: off_t) -> c_int {
+        0
+    }
+    unsafe extern "C" fn readdirplus2(
+        _: *mut SMBCCTX,
+        _: *mut SMBCFILE,
+        _: *mut stat,
+    ) -> *const libsmb_file_info {
+        ptr::null()
+    }
+    unsafe extern "C" fn getdents(
+        _: *mut SMBCCTX,
+        _: *mut SMBCFILE,
+        _: *mut smbc_dirent,
+        _: c_int,
+    ) -> c_int {
+        -1
+    }
+    unsafe extern "C" fn telldir(_: *mut SMBCCTX, _: *mut SMBCFILE) -> off_t {
+        -1
+    }
+    unsafe extern "C" fn stat(_: *mut SMBCCTX, _: *const c_char, _: *mut stat) -> c_int {
+        -1
+    }
+    unsafe extern "C" fn closedir(_: *mut SMBCCTX, _: *mut SMBCFILE) -> c_int {
+        0
+    }
+
+    let auth = SmbcAuth::new(Arc::new(SmbcCredentials::guest()), AuthMethod::default());
+    let smbc = Arc::new(Mutex::new(SmbcPtr(ptr::null_mut(), Box::into_raw(Box::new(auth)))));
+    let path = Path::new("smb://files01/reports");
+    let handle = ptr::NonNull::dangling().as_ptr();
+    let mut dir = SmbcDirectory {
+        handle: SmbcHandle::new(&smbc, handle, path, closedir, "closedir"),
+        id: NEXT_DIR_ID.fetch_add(1, Ordering::Relaxed),
+        read: Arc::new(Mutex::new(SmbcDirPosition::default())),
+        lseekdir_fn: lseekdir,
+        readdir_fn: readdir,
+        readdirplus2_fn: readdirplus2,
+        getdents_fn: getdents,
+        telldir_fn: telldir,
+        stat_fn: stat,
+    };
+    assert!(matches!(dir.next(), Some(Err(_))));
+    assert!(dir.next().is_none());
+    assert!(dir.clone().next().is_none());
+    // seeking carries on reading
+    dir.lseekdir(0).unwrap();
+    assert!(matches!(dir.next(), Some(Err(_))));
+    assert!(dir.next().is_none());
+}
+
 // NOTE: Any weird formats can be checked against the libsmb-xxx.c files in the samba source code.
 // Feel free to make edits if they ever actually get updated (probably never)
 
@@ -42,7 +268,7 @@ macro_rules! get_fnptr {
     };
 }
 
-fn check_mut_ptr<T>(ptr: *mut T) -> IoResult<*mut T> {
+pub(crate) fn check_mut_ptr<T>(ptr: *mut T) -> IoResult<*mut T> {
     if ptr.is_null() {
         Err(Error::last_os_error())
     } else {
@@ -50,7 +276,7 @@ fn check_mut_ptr<T>(ptr: *mut T) -> IoResult<*mut T> {
     }
 }
 
-fn check_neg_result<T: Eq + From<i8>>(t: T) -> IoResult<T> {
+pub(crate) fn check_neg_result<T: Eq + From<i8>>(t: T) -> IoResult<T> {
     if t == T::from(-1) {
         Err(Error::last_os_error())
     } else {
@@ -66,11 +292,99 @@ fn is_einval<T: Eq + From<i8>>(t: T) -> IoResult<T> {
     }
 }
 
-#[derive(Clone)]
-/// a pointer to hold the smbc context
-struct SmbcPtr(*mut SMBCCTX);
+/// Whether the context percent-encodes the names readdir returns
+fn url_encoded(ptr: &SmbcPtr) -> bool {
+    unsafe { smbc_getOptionUrlEncodeReaddirEntries(ptr.0) != 0 }
+}
+
+/// Characters escaped when an entry name is put back in an smb url
+/// (libsmbclient decodes %XX sequences in the urls it is given)
+pub(crate) const URL_NAME: &AsciiSet = &CONTROLS.add(b'%');
+
+/// a pointer to hold the smbc context, along with the authentication
+/// state the context's user data points to
+pub(crate) struct SmbcPtr(*mut SMBCCTX, *mut SmbcAuth);
 unsafe impl Send for SmbcPtr {}
 unsafe impl Sync for SmbcPtr {}
+/// Lock the context for op.  A context poisoned by a panic on another
+/// thread is an error until Smbc::clear_poison is called.
+pub(crate) fn lock<'a>(
+    context: &'a Mutex<SmbcPtr>,
+    op: &'static str,
+) -> SmbcResult<MutexGuard<'a, SmbcPtr>> {
+    context.lock().map_err(|_| {
+        error!("{}: smbc context is poisoned", op);
+        SmbcError::ContextPoisoned(op)
+    })
+}
+
+/// Lock the context even when it is poisoned, for reading or replacing
+/// its settings and for closing handles
+fn lock_recover(context: &Mutex<SmbcPtr>) -> MutexGuard<'_, SmbcPtr> {
+    context.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+impl SmbcPtr {
+    /// Run a libsmbclient call on the context.  A failure becomes the
+    /// classified error of op on url (see SmbcAuth::failure).
+    pub(crate) fn call<T>(
+        &self,
+        op: &'static str,
+        url: &Path,
+        f: impl FnOnce(*mut SMBCCTX) -> IoResult<T>,
+    ) -> SmbcResult<T> {
+        let auth = self.1;
+        unsafe { (*auth).last_attempt = None };
+        f(self.0).map_err(|e| {
+            trace!(target: "smbc", "{} {:?} failed {:?}", op, url, e);
+            let auth = unsafe { &mut *auth };
+            // the connection the auth callback ran for was made, so the
+            // call failed after it
+            if auth.last_attempt.as_ref().is_some_and(|a| self.is_connected(a)) {
+                auth.last_attempt = None;
+            }
+            auth.failure(op, url, e)
+        })
+    }
+
+    /// Whether libsmbclient has the connection of an auth callback in
+    /// its server cache, which it only adds connections to once the
+    /// session is set up and the share connected
+    fn is_connected(&self, attempt: &AuthAttempt) -> bool {
+        let get_cached = match get_fnptr!(unsafe { smbc_getFunctionGetCachedServer(self.0) }) {
+            Ok(get_cached) => get_cached,
+            Err(_) => return false,
+        };
+        // the strings came from C strings, they hold no NUL
+        let cstr = |s: &str| CString::new(s).unwrap_or_default();
+        let (server, share) = (cstr(&attempt.server), cstr(&attempt.share));
+        let (workgroup, username) = (cstr(&attempt.workgroup), cstr(&attempt.username));
+        let srv = unsafe {
+            get_cached(
+                self.0,
+                server.as_ptr(),
+                share.as_ptr(),
+                workgroup.as_ptr(),
+                username.as_ptr(),
+            )
+        };
+        !srv.is_null()
+    }
+
+    /// The retry policy of the context
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        unsafe { (*self.1).retry.clone() }
+    }
+
+    /// Drop the cached server connections that have nothing open on them,
+    /// so the next call connects again
+    fn purge_cached_servers(&self) -> SmbcResult<()> {
+        let purge = get_fnptr!(unsafe { smbc_getFunctionPurgeCachedServers(self.0) })?;
+        check_neg_result(unsafe { purge(self.0) })?;
+        Ok(())
+    }
+}
+
 impl Drop for SmbcPtr {
     fn drop(&mut self) {
         if !self.0.is_null() {
@@ -78,13 +392,18 @@ impl Drop for SmbcPtr {
                 smbc_free_context(self.0, 1 as c_int);
             }
         }
+        if !self.1.is_null() {
+            unsafe {
+                drop(Box::from_raw(self.1));
+            }
+        }
     }
 }
 
 #[derive(Clone)]
 /// The Smbc Object.  Contains a pointer to a Samba context
 pub struct Smbc {
-    context: Arc<Mutex<SmbcPtr>>,
+    pub(crate) context: Arc<Mutex<SmbcPtr>>,
     pub chmod_fn:
         (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char, mode: mode_t) -> c_int),
     pub close_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, file: *mut SMBCFILE) -> c_int),
@@ -118,6 +437,11 @@ pub struct Smbc {
         list: *mut c_char,
         size: usize,
     ) -> c_int),
+    pub list_print_jobs_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        fname: *const c_char,
+        f: smbc_list_print_job_fn,
+    ) -> c_int),
     pub lseek_fn: (unsafe extern "C" fn(
         c: *mut SMBCCTX,
         file: *mut SMBCFILE,
@@ -128,13 +452,30 @@ pub struct Smbc {
         (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE, offset: off_t) -> c_int),
     pub mkdir_fn:
         (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char, mode: mode_t) -> c_int),
+    pub notify_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        dir: *mut SMBCFILE,
+        recursive: smbc_bool,
+        completion_filter: u32,
+        callback_timeout_ms: c_uint,
+        cb: smbc_notify_callback_fn,
+        private_data: *mut c_void,
+    ) -> c_int),
     pub open_fn: (unsafe extern "C" fn(
         c: *mut SMBCCTX,
         fname: *const c_char,
         flags: c_int,
         mode: mode_t,
     ) -> *mut SMBCFILE),
+    pub open_print_job_fn:
+        (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char) -> *mut SMBCFILE),
     pub opendir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char) -> *mut SMBCFILE),
+    pub print_file_fn: (unsafe extern "C" fn(
+        c_file: *mut SMBCCTX,
+        fname: *const c_char,
+        c_print: *mut SMBCCTX,
+        printq: *const c_char,
+    ) -> c_int),
     pub read_fn: (unsafe extern "C" fn(
         c: *mut SMBCCTX,
         file: *mut SMBCFILE,
@@ -142,6 +483,11 @@ pub struct Smbc {
         count: usize,
     ) -> isize),
     pub readdir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> *mut smbc_dirent),
+    pub readdirplus2_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        dir: *mut SMBCFILE,
+        st: *mut stat,
+    ) -> *const libsmb_file_info),
     pub removexattr_fn: (unsafe extern "C" fn(
         context: *mut SMBCCTX,
         fname: *const c_char,
@@ -168,6 +514,8 @@ pub struct Smbc {
         (unsafe extern "C" fn(c: *mut SMBCCTX, path: *mut c_char, st: *mut statvfs) -> c_int),
     pub telldir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> off_t),
     pub unlink_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char) -> c_int),
+    pub unlink_print_job_fn:
+        (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char, id: c_int) -> c_int),
     pub utimes_fn:
         (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char, tbuf: *mut timeval) -> c_int),
     pub write_fn: (unsafe extern "C" fn(
@@ -329,7 +677,7 @@ bitflags! {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 /// File Type
 pub enum SmbcType {
     WORKGROUP = 1,
@@ -900,51 +1248,235 @@ pub struct SmbcDirEntry {
     pub s_type: SmbcType,
     /// comment associated with the entry
     pub comment: String,
-    /// path of/to the entry
+    /// path of/to the entry: the name exactly as libsmbclient gave it, so
+    /// it round trips through SmbcDirectory::entry_url even when it is not
+    /// UTF-8 (see SmbcBuilder::unix_charset)
     pub path: PathBuf,
 }
 
-#[derive(Clone)]
-/// A samba directory
-pub struct SmbcDirectory {
+impl SmbcDirEntry {
+    /// The url of the entry in the directory at url dir
+    pub fn url(&self, dir: &SmbUrl) -> SmbUrl {
+        dir.join(&self.path)
+    }
+
+    /// The name of the entry
+    pub fn file_name(&self) -> &OsStr {
+        self.path.as_os_str()
+    }
+
+    /// The bytes of the name of the entry
+    pub fn name_bytes(&self) -> &[u8] {
+        self.path.as_os_str().as_bytes()
+    }
+
+    /// The name of the entry for display, U+FFFD in place of bytes that
+    /// are not UTF-8
+    pub fn display_name(&self) -> Cow<'_, str> {
+        self.path.to_string_lossy()
+    }
+}
+
+#[derive(Debug, Clone)]
+/// A directory entry with its metadata (see SmbcDirectory::readdirplus)
+pub struct SmbcDirPlusEntry {
+    /// name of the entry, exactly as libsmbclient gave it (see
+    /// SmbcDirEntry::path)
+    pub path: PathBuf,
+    /// the 8.3 name of the entry (empty when the server has none)
+    pub short_name: String,
+    /// size, times, DOS attributes and inode of the entry
+    pub metadata: SmbcMetadata,
+}
+
+impl SmbcDirPlusEntry {
+    /// The url of the entry in the directory at url dir
+    pub fn url(&self, dir: &SmbUrl) -> SmbUrl {
+        dir.join(&self.path)
+    }
+
+    /// The name of the entry
+    pub fn file_name(&self) -> &OsStr {
+        self.path.as_os_str()
+    }
+
+    /// The bytes of the name of the entry
+    pub fn name_bytes(&self) -> &[u8] {
+        self.path.as_os_str().as_bytes()
+    }
+
+    /// The name of the entry for display, U+FFFD in place of bytes that
+    /// are not UTF-8
+    pub fn display_name(&self) -> Cow<'_, str> {
+        self.path.to_string_lossy()
+    }
+}
+
+/// An open file or directory handle.  The clones of an SmbcFile or
+/// SmbcDirectory share one, and it is closed exactly once: by an explicit
+/// close, or when the last clone is dropped.
+pub(crate) struct SmbcHandle {
     /// the samba context
-    smbc: Arc<Mutex<SmbcPtr>>,
-    /// handle to the directory
-    handle: *mut SMBCFILE,
-    pub lseekdir_fn:
-        (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE, offset: off_t) -> c_int),
-    pub readdir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> *mut smbc_dirent),
-    pub telldir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> off_t),
+    pub(crate) smbc: Arc<Mutex<SmbcPtr>>,
+    /// the libsmbclient handle, null once closed.  Only read or replaced
+    /// while the context is locked.
+    handle: AtomicPtr<SMBCFILE>,
+    /// the offset to open a lost read-only file again at, until it is
+    /// reopened or closed
+    reopen_at: Mutex<Option<off_t>>,
+    /// smb url the handle was opened with
+    pub(crate) path: PathBuf,
+    /// close or closedir, and the name of the operation
+    close_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, file: *mut SMBCFILE) -> c_int),
+    close_op: &'static str,
+}
+
+impl SmbcHandle {
+    fn new(
+        smbc: &Arc<Mutex<SmbcPtr>>,
+        handle: *mut SMBCFILE,
+        path: &Path,
+        close_fn: unsafe extern "C" fn(c: *mut SMBCCTX, file: *mut SMBCFILE) -> c_int,
+        close_op: &'static str,
+    ) -> Arc<Self> {
+        Arc::new(SmbcHandle {
+            smbc: Arc::clone(smbc),
+            handle: AtomicPtr::new(handle),
+            reopen_at: Mutex::new(None),
+            path: path.to_path_buf(),
+            close_fn,
+            close_op,
+        })
+    }
+
+    /// The open handle, EBADF once it is closed
+    fn get(&self) -> IoResult<*mut SMBCFILE> {
+        check_mut_ptr(self.handle.load(Ordering::SeqCst))
+            .map_err(|_| Error::from_raw_os_error(EBADF))
+    }
+
+    /// Run a libsmbclient call on the open handle (see SmbcPtr::call)
+    pub(crate) fn call<T>(
+        &self,
+        ptr: &SmbcPtr,
+        op: &'static str,
+        f: impl FnOnce(*mut SMBCCTX, *mut SMBCFILE) -> IoResult<T>,
+    ) -> SmbcResult<T> {
+        ptr.call(op, &self.path, |ctx| f(ctx, self.get()?))
+    }
+
+    /// Close the handle if it is still open.  ptr is the locked context.
+    pub(crate) fn close(&self, ptr: &SmbcPtr) -> SmbcResult<()> {
+        self.reopen_at.lock().unwrap_or_else(PoisonError::into_inner).take();
+        let handle = self.handle.swap(ptr::null_mut(), Ordering::SeqCst);
+        if handle.is_null() {
+            return Ok(());
+        }
+        trace!(target: "smbc", "{} {:?}", self.close_op, self.path);
+        ptr.call(self.close_op, &self.path, |ctx| {
+            check_neg_result(unsafe { (self.close_fn)(ctx, handle) })
+        })?;
+        Ok(())
+    }
 }
 
-impl Drop for SmbcDirectory {
+impl Drop for SmbcHandle {
     fn drop(&mut self) {
-        if !self.handle.is_null() {
-            let ptr = match self.smbc.lock() {
-                Ok(p) => p,
-                Err(e) => {
-                    error!("Poisoned mutex {:?}", e);
-                    panic!("POISONED MUTEX {:?}!!!!", e)
-                }
-            };
-
-            trace!(target: "smbc", "closing smbc file");
-            unsafe {
-                smbc_getFunctionClosedir(ptr.0).map(|f| f(ptr.0, self.handle));
+        if !self.handle.get_mut().is_null() {
+            let ptr = lock_recover(&self.smbc);
+            if let Err(e) = self.close(&ptr) {
+                error!("{}", e);
             }
         }
     }
 }
 
+/// The id of the next opened directory (see SmbcDirCursor)
+static NEXT_DIR_ID: AtomicU64 = AtomicU64::new(1);
+
+#[derive(Debug, Clone, Default)]
+/// How far readdir and getdents have read an SmbcDirectory
+struct SmbcDirPosition {
+    /// entries read since the start, None after an lseekdir elsewhere
+    count: Option<u64>,
+    /// the name of the last entry read
+    last: Option<PathBuf>,
+    /// an iteration of the directory ended with an error, it yields no
+    /// more entries until the directory is seeked
+    failed: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A place in a directory listing (see SmbcDirectory::cursor), to carry
+/// on from in the same directory or in the directory opened again after
+/// a reconnect
+pub struct SmbcDirCursor {
+    /// the directory the cursor was taken from, 0 for none
+    dir: u64,
+    /// the telldir offset in that directory, None at its end
+    offset: Option<off_t>,
+    count: Option<u64>,
+    last: Option<PathBuf>,
+}
+
+impl SmbcDirCursor {
+    /// A cursor after position entries, the last of them named last (a
+    /// cursor saved with position and last_name, say across restarts)
+    pub fn new(position: Option<u64>, last: Option<PathBuf>) -> Self {
+        SmbcDirCursor { dir: 0, offset: None, count: position, last }
+    }
+
+    /// The number of entries read before the cursor, when known
+    pub fn position(&self) -> Option<u64> {
+        self.count
+    }
+
+    /// The name of the last entry read before the cursor
+    pub fn last_name(&self) -> Option<&Path> {
+        self.last.as_deref()
+    }
+}
+
+#[derive(Clone)]
+/// A samba directory.  Clones share the open directory.
+pub struct SmbcDirectory {
+    /// the open directory
+    handle: Arc<SmbcHandle>,
+    /// which opened directory this is, for its cursors
+    id: u64,
+    /// how far readdir and getdents have read, shared by the clones
+    read: Arc<Mutex<SmbcDirPosition>>,
+    pub lseekdir_fn:
+        (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE, offset: off_t) -> c_int),
+    pub readdir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> *mut smbc_dirent),
+    pub readdirplus2_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        dir: *mut SMBCFILE,
+        st: *mut stat,
+    ) -> *const libsmb_file_info),
+    pub getdents_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        dir: *mut SMBCFILE,
+        dirp: *mut smbc_dirent,
+        count: c_int,
+    ) -> c_int),
+    pub telldir_fn: (unsafe extern "C" fn(c: *mut SMBCCTX, dir: *mut SMBCFILE) -> off_t),
+    pub stat_fn:
+        (unsafe extern "C" fn(c: *mut SMBCCTX, fname: *const c_char, st: *mut stat) -> c_int),
+}
+
 #[derive(Clone)]
-/// A samba file
+/// A samba file.  Clones share the open file (and its offset).
 pub struct SmbcFile {
-    /// the samba context
-    smbc: Arc<Mutex<SmbcPtr>>,
-    /// handle to the file
-    handle: *mut SMBCFILE,
+    /// the open file
+    handle: Arc<SmbcHandle>,
+    /// the flags of a file opened read-only, to open it again with when
+    /// its connection is lost (see RetryPolicy)
+    reopen_flags: Option<OFlag>,
     pub fstat_fn:
         (unsafe extern "C" fn(c: *mut SMBCCTX, file: *mut SMBCFILE, st: *mut stat) -> c_int),
+    pub fstatvfs_fn:
+        (unsafe extern "C" fn(c: *mut SMBCCTX, file: *mut SMBCFILE, st: *mut statvfs) -> c_int),
     pub ftruncate_fn:
         (unsafe extern "C" fn(c: *mut SMBCCTX, f: *mut SMBCFILE, size: off_t) -> c_int),
     pub lseek_fn: (unsafe extern "C" fn(
@@ -953,6 +1485,12 @@ pub struct SmbcFile {
         offset: off_t,
         whence: c_int,
     ) -> off_t),
+    pub open_fn: (unsafe extern "C" fn(
+        c: *mut SMBCCTX,
+        fname: *const c_char,
+        flags: c_int,
+        mode: mode_t,
+    ) -> *mut SMBCFILE),
     pub read_fn: (unsafe extern "C" fn(
         c: *mut SMBCCTX,
         file: *mut SMBCFILE,
@@ -967,26 +1505,8 @@ pub struct SmbcFile {
     ) -> isize),
 }
 
-impl Drop for SmbcFile {
-    fn drop(&mut self) {
-        if !self.handle.is_null() {
-            let ptr = match self.smbc.lock() {
-                Ok(p) => p,
-                Err(e) => {
-                    error!("Poisoned mutex {:?}", e);
-                    panic!("POISONED MUTEX {:?}!!!!", e)
-                }
-            };
-
-            unsafe {
-                smbc_getFunctionClose(ptr.0).map(|f| f(ptr.0, self.handle));
-            }
-        }
-    }
-}
-
 impl Smbc {
-    /// set the user authentication data
+    /// set the default user authentication data
     ///
     /// @param wg   The workgroup of the user
     ///
@@ -994,204 +1514,262 @@ impl Smbc {
     ///
     /// @param pw   The password of the user
     ///
-    /// @note: This applies to all contexts
+    /// @note: This applies to contexts created afterwards that do not set
+    ///        their own credentials with SmbcBuilder::credentials
+    #[deprecated(note = "use SmbcBuilder::credentials or Smbc::set_credentials")]
     pub fn set_data(wg: String, un: String, pw: String) {
-        let mut data = match USER_DATA.lock() {
-            Ok(e) => e,
-            Err(e) => panic!("Error {:?} Mutex poisoned!", e),
-        };
+        let mut data = USER_DATA.lock().unwrap_or_else(|e| e.into_inner());
         data[0] = wg;
         data[1] = un;
         data[2] = pw;
     }
 
+    /// The credentials set by set_data (or the default guest)
+    pub(crate) fn default_credentials() -> SmbcCredentials {
+        let data = USER_DATA.lock().unwrap_or_else(|e| e.into_inner());
+        SmbcCredentials::new(
+            data.first().map_or("WORKGROUP", |e| e.as_str()),
+            data.get(1).map_or("guest", |e| e.as_str()),
+            data.get(2).map_or("", |e| e.as_str()),
+        )
+    }
+
     /// new function with Authentication built in, create a new context
     ///
     /// @param level    the debug level of the context
     ///
     /// @return         return a new Smbc context with user authentication
     ///                 set by set_data (or default). Error should it fail.
+    ///
+    /// @note: Debug output goes to the `log` crate (target "smbc") and one
+    ///        share per server is on.
+    ///        Use SmbcBuilder to configure the context yourself.
     pub fn new_with_auth(level: i32) -> SmbcResult<Smbc> {
+        SmbcBuilder::new()
+            .debug_level(level)
+            .log_target(SmbcLogTarget::Log)
+            .one_share_per_server(true)
+            .build()
+    }
+
+    /// Wrap an initialized context and look up its function table.
+    /// The context's user data must point to auth.
+    pub(crate) unsafe fn from_ptr(ptr: *mut SMBCCTX, auth: Box<SmbcAuth>) -> SmbcResult<Smbc> {
+        let context = Arc::new(Mutex::new(SmbcPtr(ptr, Box::into_raw(auth))));
+        Ok(Smbc {
+            context,
+            chmod_fn: get_fnptr!(smbc_getFunctionChmod(ptr))?,
+            close_fn: get_fnptr!(smbc_getFunctionClose(ptr))?,
+            closedir_fn: get_fnptr!(smbc_getFunctionClosedir(ptr))?,
+            creat_fn: get_fnptr!(smbc_getFunctionCreat(ptr))?,
+            fstat_fn: get_fnptr!(smbc_getFunctionFstat(ptr))?,
+            fstatvfs_fn: get_fnptr!(smbc_getFunctionFstatVFS(ptr))?,
+            fstatdir_fn: get_fnptr!(smbc_getFunctionFstatdir(ptr))?,
+            ftruncate_fn: get_fnptr!(smbc_getFunctionFtruncate(ptr))?,
+            getdents_fn: get_fnptr!(smbc_getFunctionGetdents(ptr))?,
+            getxattr_fn: get_fnptr!(smbc_getFunctionGetxattr(ptr))?,
+            listxattr_fn: get_fnptr!(smbc_getFunctionListxattr(ptr))?,
+            list_print_jobs_fn: get_fnptr!(smbc_getFunctionListPrintJobs(ptr))?,
+            lseek_fn: get_fnptr!(smbc_getFunctionLseek(ptr))?,
+            lseekdir_fn: get_fnptr!(smbc_getFunctionLseekdir(ptr))?,
+            mkdir_fn: get_fnptr!(smbc_getFunctionMkdir(ptr))?,
+            notify_fn: get_fnptr!(smbc_getFunctionNotify(ptr))?,
+            open_fn: get_fnptr!(smbc_getFunctionOpen(ptr))?,
+            open_print_job_fn: get_fnptr!(smbc_getFunctionOpenPrintJob(ptr))?,
+            opendir_fn: get_fnptr!(smbc_getFunctionOpendir(ptr))?,
+            print_file_fn: get_fnptr!(smbc_getFunctionPrintFile(ptr))?,
+            read_fn: get_fnptr!(smbc_getFunctionRead(ptr))?,
+            readdir_fn: get_fnptr!(smbc_getFunctionReaddir(ptr))?,
+            readdirplus2_fn: get_fnptr!(smbc_getFunctionReaddirPlus2(ptr))?,
+            removexattr_fn: get_fnptr!(smbc_getFunctionRemovexattr(ptr))?,
+            rename_fn: get_fnptr!(smbc_getFunctionRename(ptr))?,
+            rmdir_fn: get_fnptr!(smbc_getFunctionRmdir(ptr))?,
+            setxattr_fn: get_fnptr!(smbc_getFunctionSetxattr(ptr))?,
+            stat_fn: get_fnptr!(smbc_getFunctionStat(ptr))?,
+            statvfs_fn: get_fnptr!(smbc_getFunctionStatVFS(ptr))?,
+            telldir_fn: get_fnptr!(smbc_getFunctionTelldir(ptr))?,
+            unlink_fn: get_fnptr!(smbc_getFunctionUnlink(ptr))?,
+            unlink_print_job_fn: get_fnptr!(smbc_getFunctionUnlinkPrintJob(ptr))?,
+            utimes_fn: get_fnptr!(smbc_getFunctionUtimes(ptr))?,
+            write_fn: get_fnptr!(smbc_getFunctionWrite(ptr))?,
+        })
+    }
+
+    /// Change the credentials this context authenticates with.
+    /// Servers that are already connected keep their session, new
+    /// connections use the new credentials.
+    pub fn set_credentials(&self, credentials: SmbcCredentials) {
+        self.set_credential_provider(Arc::new(credentials));
+    }
+
+    /// Change where this context looks up the credentials for each
+    /// server and share.  Servers that are already connected keep their
+    /// session.
+    pub fn set_credential_provider(&self, provider: Arc<dyn CredentialProvider>) {
+        let ptr = lock_recover(&self.context);
+        let auth = ptr.1;
         unsafe {
-            smbc_init(Some(Self::set_data_wrapper), level);
-            let ctx = check_mut_ptr(smbc_new_context())?;
-            smbc_setOptionDebugToStderr(ctx, 1);
-            smbc_setOptionUserData(ctx, Self::auth_wrapper as *mut c_void);
-            smbc_setFunctionAuthData(ctx, Some(Self::set_data_wrapper));
-            smbc_setFunctionAuthDataWithContext(ctx, Some(Self::auth_wrapper));
-            smbc_setOptionOneSharePerServer(ctx, 1);
-            let data = match USER_DATA.lock() {
-                Ok(e) => e,
-                Err(e) => panic!("Error {:?}, Mutex poisoned!", e),
-            };
-            let (wg, un, pw) = (
-                match data.get(0) {
-                    Some(e) => e.to_string(),
-                    None => "WORKGROUP".to_string(),
-                },
-                match data.get(1) {
-                    Some(e) => e.to_string(),
-                    None => "guest".to_string(),
-                },
-                match data.get(2) {
-                    Some(e) => e.to_string(),
-                    None => "".to_string(),
-                },
-            );
-            smbc_set_credentials_with_fallback(
-                ctx,
-                wg.as_ptr() as *const c_char,
-                un.as_ptr() as *const c_char,
-                pw.as_ptr() as *const c_char,
-            );
-            smbc_setDebug(ctx, level);
-            let ptr: *mut SMBCCTX = match check_mut_ptr(smbc_init_context(ctx)) {
-                Ok(p) => p,
-                Err(e) => {
-                    trace!(target: "smbc", "smbc_init failed {:?}", e);
-                    smbc_free_context(ctx, 1 as c_int);
-                    ptr::null_mut()
-                }
-            };
-            smbc_set_context(ptr);
-            Ok(Smbc {
-                context: Arc::new(Mutex::new(SmbcPtr(ptr))),
-                chmod_fn: get_fnptr!(smbc_getFunctionChmod(ptr))?,
-                close_fn: get_fnptr!(smbc_getFunctionClose(ptr))?,
-                closedir_fn: get_fnptr!(smbc_getFunctionClosedir(ptr))?,
-                creat_fn: get_fnptr!(smbc_getFunctionCreat(ptr))?,
-                fstat_fn: get_fnptr!(smbc_getFunctionFstat(ptr))?,
-                fstatvfs_fn: get_fnptr!(smbc_getFunctionFstatVFS(ptr))?,
-                fstatdir_fn: get_fnptr!(smbc_getFunctionFstatdir(ptr))?,
-                ftruncate_fn: get_fnptr!(smbc_getFunctionFtruncate(ptr))?,
-                getdents_fn: get_fnptr!(smbc_getFunctionGetdents(ptr))?,
-                getxattr_fn: get_fnptr!(smbc_getFunctionGetxattr(ptr))?,
-                listxattr_fn: get_fnptr!(smbc_getFunctionListxattr(ptr))?,
-                lseek_fn: get_fnptr!(smbc_getFunctionLseek(ptr))?,
-                lseekdir_fn: get_fnptr!(smbc_getFunctionLseekdir(ptr))?,
-                mkdir_fn: get_fnptr!(smbc_getFunctionMkdir(ptr))?,
-                open_fn: get_fnptr!(smbc_getFunctionOpen(ptr))?,
-                opendir_fn: get_fnptr!(smbc_getFunctionOpendir(ptr))?,
-                read_fn: get_fnptr!(smbc_getFunctionRead(ptr))?,
-                readdir_fn: get_fnptr!(smbc_getFunctionReaddir(ptr))?,
-                removexattr_fn: get_fnptr!(smbc_getFunctionRemovexattr(ptr))?,
-                rename_fn: get_fnptr!(smbc_getFunctionRename(ptr))?,
-                rmdir_fn: get_fnptr!(smbc_getFunctionRmdir(ptr))?,
-                setxattr_fn: get_fnptr!(smbc_getFunctionSetxattr(ptr))?,
-                stat_fn: get_fnptr!(smbc_getFunctionStat(ptr))?,
-                statvfs_fn: get_fnptr!(smbc_getFunctionStatVFS(ptr))?,
-                telldir_fn: get_fnptr!(smbc_getFunctionTelldir(ptr))?,
-                unlink_fn: get_fnptr!(smbc_getFunctionUnlink(ptr))?,
-                utimes_fn: get_fnptr!(smbc_getFunctionUtimes(ptr))?,
-                write_fn: get_fnptr!(smbc_getFunctionWrite(ptr))?,
-            })
+            (*auth).provider = provider;
         }
     }
 
-    /// An external C function used by new_with_auth in order to provide
+    /// Whether a thread panicked while using this context (or a file or
+    /// directory opened from it).  Calls then fail with ContextPoisoned.
+    pub fn is_poisoned(&self) -> bool {
+        self.context.is_poisoned()
+    }
+
+    /// Use a poisoned context again.  A panic in Rust code cannot leave
+    /// libsmbclient half way through a call, so the context itself is
+    /// intact; reopen any file or directory whose position the panicking
+    /// thread may have left somewhere unexpected.
+    pub fn clear_poison(&self) {
+        self.context.clear_poison();
+    }
+
+    /// SmbcPtr::call of op on url, made again while it fails with an error
+    /// the retry policy of the context retries.  The context is locked for
+    /// each attempt and unlocked while waiting for the next, which purges
+    /// the cached servers first.  Only for calls that are safe to repeat.
+    pub(crate) fn call_retrying<T>(
+        &self,
+        op: &'static str,
+        url: &Path,
+        mut f: impl FnMut(*mut SMBCCTX) -> IoResult<T>,
+    ) -> SmbcResult<T> {
+        let policy = lock(&self.context, op)?.retry_policy();
+        retry(
+            policy.as_ref(),
+            || lock(&self.context, op)?.purge_cached_servers(),
+            || lock(&self.context, op)?.call(op, url, &mut f),
+        )
+    }
+
+    /// Change the retry policy of this context, None to retry nothing.
+    /// Files opened already use the new policy too.
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        let ptr = lock_recover(&self.context);
+        let auth = ptr.1;
+        unsafe { (*auth).retry = policy };
+    }
+
+    /// The retry policy of this context (see SmbcBuilder::retry_policy)
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        lock_recover(&self.context).retry_policy()
+    }
+
+    /// Drop the cached connections to servers that have no file or
+    /// directory open, so that the next call on them connects again
+    /// (after a server restarted, say).
+    pub fn purge_cached_servers(&self) -> SmbcResult<()> {
+        lock(&self.context, "purge_cached_servers")?.purge_cached_servers()
+    }
+
+    /// The authentication method this context was built with
+    pub fn auth_method(&self) -> AuthMethod {
+        let ptr = lock_recover(&self.context);
+        unsafe { (*ptr.1).method }
+    }
+
+    /// Connect and authenticate to a server or share without doing
+    /// anything else, to check the credentials up front.
+    ///
+    /// @param path     smb://server or smb://server/share
+    ///
+    /// @return         Nothing on success.  When the server refuses the
+    ///                 session (EACCES or EPERM) the error is
+    ///                 AuthenticationFailed, naming the authentication
+    ///                 method that was tried, otherwise the error
+    ///                 of the failed connection (see SmbcError).
+    ///                 NOTE: libsmbclient also reports EPERM when the
+    ///                 context requires encryption and the server cannot
+    ///                 encrypt the connection.
+    ///
+    /// @note: Servers that are already connected are not authenticated
+    ///        again, a share they deny is AccessDenied.
+    pub fn authenticate(&self, path: &Path) -> SmbcResult<()> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "authenticate")?;
+        let handle = ptr.call("authenticate", path, |ctx| {
+            check_mut_ptr(unsafe { (self.opendir_fn)(ctx, cpath.as_ptr()) })
+        })?;
+        unsafe { (self.closedir_fn)(ptr.0, handle) };
+        Ok(())
+    }
+
+    /// The SMB encryption level this context connects with.  Under
+    /// Require every connection is encrypted: libsmbclient refuses servers
+    /// that cannot encrypt.
+    ///
+    /// @note: libsmbclient cannot report whether a connection actually got
+    ///        sealed, so under Request there is no telling whether the
+    ///        server encrypted it.
+    pub fn encryption_level(&self) -> EncryptionLevel {
+        let ptr = lock_recover(&self.context);
+        EncryptionLevel::from(unsafe { smbc_getOptionSmbEncryptionLevel(ptr.0) } as u32)
+    }
+
+    /// The SMB dialects this context may negotiate, None when it uses the
+    /// smb.conf (or libsmbclient) defaults
+    pub fn protocol_range(&self) -> Option<ProtocolRange> {
+        let ptr = lock_recover(&self.context);
+        unsafe { (*ptr.1).protocols }
+    }
+
+    /// An external C function used by SmbcBuilder in order to provide
     /// user authentication for the Smbc context.
-    /// This authentication function includes a context parameter
-    extern "C" fn auth_wrapper(
-        _ctx: *mut SMBCCTX,
+    /// This authentication function includes a context parameter, the
+    /// credential provider is looked up through the context's user data.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) extern "C" fn auth_wrapper(
+        ctx: *mut SMBCCTX,
         srv: *const c_char,
         shr: *const c_char,
         wg: *mut c_char,
-        _wglen: c_int,
+        wglen: c_int,
         un: *mut c_char,
-        _unlen: c_int,
+        unlen: c_int,
         pw: *mut c_char,
-        _pwlen: c_int,
+        pwlen: c_int,
     ) {
         unsafe {
-            let t_srv = CStr::from_ptr(srv);
-            let t_shr = CStr::from_ptr(shr);
-            let _srv = t_srv.as_ptr();
-            let _shr = t_shr.as_ptr();
+            let t_srv = CStr::from_ptr(srv).to_string_lossy();
+            let t_shr = CStr::from_ptr(shr).to_string_lossy();
             trace!(target: "smbc", "authenticating on {:?}\\{:?}", &t_srv, &t_shr);
 
-            //either use the provided credentials or the default guest
-            let data = match USER_DATA.lock() {
-                Ok(e) => e,
-                Err(e) => panic!("Error {:?}, Mutex poisoned!", e),
-            };
-            let (workgroup, username, password) = (
-                match data.get(0) {
-                    Some(e) => e.to_string(),
-                    None => "WORKGROUP".to_string(),
-                },
-                match data.get(1) {
-                    Some(e) => e.to_string(),
-                    None => "guest".to_string(),
-                },
-                match data.get(2) {
-                    Some(e) => e.to_string(),
-                    None => "\n".to_string(),
-                },
-            );
-            let (wg_ptr, un_ptr, pw_ptr) = (
-                CString::from_vec_unchecked(workgroup.clone().into_bytes()),
-                CString::from_vec_unchecked(username.clone().into_bytes()),
-                CString::from_vec_unchecked(password.clone().into_bytes()),
-            );
-            trace!(target: "smbc", "credentials: {:?}\\{:?} {:?}", &workgroup, &username, &password);
-            let (wglen, unlen, pwlen) = (workgroup.len(), username.len(), password.len());
-
-            strncpy(wg, wg_ptr.as_ptr(), wglen);
-            strncpy(un, un_ptr.as_ptr(), unlen);
-            strncpy(pw, pw_ptr.as_ptr(), pwlen);
+            let auth = smbc_getOptionUserData(ctx) as *mut SmbcAuth;
+            if auth.is_null() {
+                error!("context has no credentials, using the defaults");
+                SmbcAuth::new(Arc::new(Self::default_credentials()), AuthMethod::default())
+                    .fill(&t_srv, &t_shr, wg, wglen, un, unlen, pw, pwlen);
+                return;
+            }
+            (*auth).fill(&t_srv, &t_shr, wg, wglen, un, unlen, pw, pwlen);
         }
     }
 
     /// Auth wrapper
     ///
-    /// An external C function used by new_with_auth in order to provide
-    /// user authentication for the Smbc context
-    extern "C" fn set_data_wrapper(
+    /// An external C function used by smbc_init in order to provide
+    /// user authentication for the global compatibility context.
+    /// It can only use the credentials set by set_data.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) extern "C" fn set_data_wrapper(
         srv: *const c_char,
         shr: *const c_char,
         wg: *mut c_char,
-        _wglen: c_int,
+        wglen: c_int,
         un: *mut c_char,
-        _unlen: c_int,
+        unlen: c_int,
         pw: *mut c_char,
-        _pwlen: c_int,
+        pwlen: c_int,
     ) {
         unsafe {
-            let t_srv = CStr::from_ptr(srv);
-            let t_shr = CStr::from_ptr(shr);
-            let _srv = t_srv.as_ptr();
-            let _shr = t_shr.as_ptr();
+            let t_srv = CStr::from_ptr(srv).to_string_lossy();
+            let t_shr = CStr::from_ptr(shr).to_string_lossy();
             trace!(target: "smbc", "authenticating on {:?}\\{:?}", &t_srv, &t_shr);
-            //either use the provided credentials or the default guest
-            let data = match USER_DATA.lock() {
-                Ok(e) => e,
-                Err(e) => panic!("Error {:?}, Mutex poisoned!", e),
-            };
-            let (workgroup, username, password) = (
-                match data.get(0) {
-                    Some(e) => e.to_string(),
-                    None => "WORKGROUP".to_string(),
-                },
-                match data.get(1) {
-                    Some(e) => e.to_string(),
-                    None => "guest".to_string(),
-                },
-                match data.get(2) {
-                    Some(e) => e.to_string(),
-                    None => "".to_string(),
-                },
-            );
-            let (wg_ptr, un_ptr, pw_ptr) = (
-                CString::from_vec_unchecked(workgroup.clone().into_bytes()),
-                CString::from_vec_unchecked(username.clone().into_bytes()),
-                CString::from_vec_unchecked(password.clone().into_bytes()),
-            );
-            trace!(target: "smbc", "cred: {:?}\\{:?} {:?}", &workgroup, &username, &password);
-            let (wglen, unlen, pwlen) = (workgroup.len(), username.len(), password.len());
-
-            strncpy(wg, wg_ptr.as_ptr(), wglen);
-            strncpy(un, un_ptr.as_ptr(), unlen);
-            strncpy(pw, pw_ptr.as_ptr(), pwlen);
+            SmbcAuth::new(Arc::new(Self::default_credentials()), AuthMethod::default())
+                .fill(&t_srv, &t_shr, wg, wglen, un, unlen, pw, pwlen);
         }
     }
 
@@ -1217,33 +1795,20 @@ impl Smbc {
     ///                   not exist.
     ///                   - ENODEV The requested share does not exist.
     pub fn create(&self, path: &Path, mode: Mode) -> SmbcResult<SmbcFile> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "create")?;
         trace!(target: "smbc", "Sucessfully retrieved context, attempting to apply function");
 
         unsafe {
-            let handle =
-                check_mut_ptr((self.creat_fn)(ptr.0, path.as_ptr(), mode.bits() as mode_t))?;
+            let handle = ptr.call("create", path, |ctx| {
+                check_mut_ptr((self.creat_fn)(ctx, cpath.as_ptr(), mode.bits() as mode_t))
+            })?;
             trace!(target: "smbc", "Returned value is {:?}", handle);
             if (handle as i64) < 0 {
                 trace!(target: "smbc", "Error: neg handle");
             }
-            Ok(SmbcFile {
-                smbc: Arc::clone(&self.context),
-                handle,
-                fstat_fn: self.fstat_fn,
-                ftruncate_fn: self.ftruncate_fn,
-                lseek_fn: self.lseek_fn,
-                read_fn: self.read_fn,
-                write_fn: self.write_fn,
-            })
+            Ok(self.file(handle, path))
         }
     }
 
@@ -1271,18 +1836,14 @@ impl Smbc {
     /// For more details on how chmod works, please go to:
     /// https://ftp.samba.org/pub/pub/unpacked/SOC/2005/SAMBA_3_0/source/libsmb/libsmbclient.c
     pub fn chmod(&self, path: &Path, mode: Mode) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "chmod")?;
         trace!(target: "smbc", "Sucessfully retrieved context, attempting to apply function");
 
-        check_neg_result(unsafe { (self.chmod_fn)(ptr.0, path.as_ptr(), mode.bits() as mode_t) })?;
+        ptr.call("chmod", path, |ctx| {
+            check_neg_result(unsafe { (self.chmod_fn)(ctx, cpath.as_ptr(), mode.bits() as mode_t) })
+        })?;
 
         trace!(target: "smbc", "Chmod_fn ran");
         Ok(())
@@ -1290,6 +1851,7 @@ impl Smbc {
 
     ///@ingroup file
     /// Open a file on an SMB server.
+    /// (SmbcOpenOptions builds the flags the way std::fs::OpenOptions does)
     ///
     /// @param path      The smb url of the file to be opened.
     ///
@@ -1342,32 +1904,37 @@ impl Smbc {
     ///                  UNIX permissions the file has intact though.
     ///                 
     pub fn open(&self, path: &Path, flags: OFlag, mode: Mode) -> SmbcResult<SmbcFile> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "open")?;
 
         trace!(target: "smbc", "Sucessfully retrieved context, attempting to apply function");
-        let handle = check_mut_ptr(unsafe {
-            (self.open_fn)(ptr.0, path.as_ptr(), flags.bits(), mode.bits())
+        let handle = ptr.call("open", path, |ctx| {
+            check_mut_ptr(unsafe { (self.open_fn)(ctx, cpath.as_ptr(), flags.bits(), mode.bits()) })
         })?;
         if (handle as i64) < 0 {
             trace!(target: "smbc", "neg handle");
         }
-        Ok(SmbcFile {
-            smbc: Arc::clone(&self.context),
-            handle,
+        let mut file = self.file(handle, path);
+        if flags & OFlag::O_ACCMODE == OFlag::O_RDONLY {
+            file.reopen_flags = Some(flags - (OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_TRUNC));
+        }
+        Ok(file)
+    }
+
+    /// The SmbcFile of a handle libsmbclient opened at path
+    pub(crate) fn file(&self, handle: *mut SMBCFILE, path: &Path) -> SmbcFile {
+        SmbcFile {
+            handle: SmbcHandle::new(&self.context, handle, path, self.close_fn, "close"),
+            reopen_flags: None,
             fstat_fn: self.fstat_fn,
+            fstatvfs_fn: self.fstatvfs_fn,
             ftruncate_fn: self.ftruncate_fn,
             lseek_fn: self.lseek_fn,
+            open_fn: self.open_fn,
             read_fn: self.read_fn,
             write_fn: self.write_fn,
-        })
+        }
     }
 
     /// Open a directory used to obtain directory entries.
@@ -1386,46 +1953,75 @@ impl Smbc {
     ///                  - EPERM the workgroup could not be found.
     ///                  - ENODEV the workgroup or server could not be found.
     pub fn opendir(&self, path: &Path) -> SmbcResult<SmbcDirectory> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
-        trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        trace!(target: "smbc", "Sucessfully retrieved context, attempting to apply function");
-        let handle = check_mut_ptr(unsafe { (self.opendir_fn)(ptr.0, path.as_ptr()) })?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let handle = self.call_retrying("opendir", path, |ctx| {
+            check_mut_ptr(unsafe { (self.opendir_fn)(ctx, cpath.as_ptr()) })
+        })?;
         if (handle as i64) < 0 {
             trace!(target: "smbc", "Error: neg directory handle");
         }
         Ok(SmbcDirectory {
-            smbc: Arc::clone(&self.context),
-            handle,
+            handle: SmbcHandle::new(&self.context, handle, path, self.closedir_fn, "closedir"),
+            id: NEXT_DIR_ID.fetch_add(1, Ordering::Relaxed),
+            read: Arc::new(Mutex::new(SmbcDirPosition {
+                count: Some(0),
+                last: None,
+                failed: false,
+            })),
             lseekdir_fn: self.lseekdir_fn,
             readdir_fn: self.readdir_fn,
+            readdirplus2_fn: self.readdirplus2_fn,
+            getdents_fn: self.getdents_fn,
             telldir_fn: self.telldir_fn,
+            stat_fn: self.stat_fn,
         })
     }
 
+    /// Watch a directory for changes (SMB change notification).
+    ///
+    /// @param path      The smb url of the directory to watch
+    ///
+    /// @param recursive Whether to report changes in subdirectories too
+    ///
+    /// @param filter    The kinds of change to report
+    ///
+    /// @return          The watch, an iterator of the changes.  Errors
+    ///                  are as for opendir.
+    ///
+    /// NOTE: libsmbclient waits for changes with the context locked, so
+    /// while a watch waits no other call can use this context (or a file
+    /// or directory opened from it).  Watch from an Smbc of its own.
+    pub fn watch(
+        &self,
+        path: &Path,
+        recursive: bool,
+        filter: NotifyFilter,
+    ) -> SmbcResult<SmbcWatch> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "notify")?;
+        let handle = ptr.call("notify", path, |ctx| {
+            check_mut_ptr(unsafe { (self.opendir_fn)(ctx, cpath.as_ptr()) })
+        })?;
+        Ok(SmbcWatch::new(
+            SmbcHandle::new(&self.context, handle, path, self.closedir_fn, "closedir"),
+            self.notify_fn,
+            recursive,
+            filter,
+        ))
+    }
+
     /// Please NOTE that MODE does not matter, since the
     /// function never actually uses the input mode...
     /// See https://ftp.samba.org/pub/pub/unpacked/SOC/2005/SAMBA_3_0/source/libsmb/libsmbclient.cg
     /// for details
     pub fn mkdir(&self, path: &Path, mode: Mode) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "mkdir")?;
         trace!(target: "smbc", "Sucessfully retrieved context, attempting to apply function");
-        let handle =
-            check_neg_result(unsafe { (self.mkdir_fn)(ptr.0, path.as_ptr(), mode.bits()) })?;
+        let handle = ptr.call("mkdir", path, |ctx| {
+            check_neg_result(unsafe { (self.mkdir_fn)(ctx, cpath.as_ptr(), mode.bits()) })
+        })?;
         if i64::from(handle) < 0 {
             trace!(target: "smbc", "Error: neg directory handle");
         }
@@ -1466,19 +2062,15 @@ impl Smbc {
     ///                  - ENOMEM Insufficient kernel memory was available.
     ///                  - EEXIST The target file, nurl, already exists.
     pub fn rename(&self, oldpath: &Path, newpath: &Path) -> SmbcResult<()> {
-        let oldpath = CString::new(oldpath.as_os_str().as_bytes())?;
-        let newpath = CString::new(newpath.as_os_str().as_bytes())?;
+        let c_oldpath = CString::new(oldpath.as_os_str().as_bytes())?;
+        let c_newpath = CString::new(newpath.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retrieve context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "rename")?;
         trace!(target: "smbc", "Successfully retrieved context, attempting to apply function");
-        check_neg_result(unsafe {
-            (self.rename_fn)(ptr.0, oldpath.as_ptr(), ptr.0, newpath.as_ptr())
+        ptr.call("rename", oldpath, |ctx| {
+            check_neg_result(unsafe {
+                (self.rename_fn)(ctx, c_oldpath.as_ptr(), ctx, c_newpath.as_ptr())
+            })
         })?;
         Ok(())
     }
@@ -1498,17 +2090,13 @@ impl Smbc {
     ///                 - ENOTEMPTY directory contains entries.
     ///                 - ENOMEM Insufficient kernel memory was available.
     pub fn rmdir(&self, path: &Path) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         trace!(target: "smbc", "Attempting to retreive context");
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.context, "rmdir")?;
         trace!(target: "smbc", "Successfully retrieved context, attempting to apply function");
-        check_neg_result(unsafe { (self.rmdir_fn)(ptr.0, path.as_ptr()) })?;
+        ptr.call("rmdir", path, |ctx| {
+            check_neg_result(unsafe { (self.rmdir_fn)(ctx, cpath.as_ptr()) })
+        })?;
         Ok(())
     }
 
@@ -1530,24 +2118,43 @@ impl Smbc {
     /// if map hidden = yes, you can add the XOTH flag to mode, (OTH can be 4, 5)
     ///
     /// m_time, c_time, a_time return the number of seconds since epoch
+    /// (c_time is the change time, not the creation time)
     ///
     /// See https://ftp.samba.org/pub/pub/unpacked/SOC/2005/SAMBA_3_0/source/libsmb/libsmbclient.c
     /// for details (you'll be surprised at how much of this is hard coded...)
-    pub fn stat(&self, path: &Path) -> SmbcResult<stat> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+    pub fn stat(&self, path: &Path) -> SmbcResult<SmbcMetadata> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         let mut stat_buf: stat = unsafe { zeroed::<stat>() };
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let res = check_neg_result(unsafe { (self.stat_fn)(ptr.0, path.as_ptr(), &mut stat_buf) })?;
+        let res = self.call_retrying("stat", path, |ctx| {
+            check_neg_result(unsafe { (self.stat_fn)(ctx, cpath.as_ptr(), &mut stat_buf) })
+        })?;
         if i64::from(res) < 0 {
             trace!(target: "smbc", "stat failed");
         }
-        Ok(stat_buf)
+        Ok(SmbcMetadata::from(stat_buf))
+    }
+
+    /// statvfs
+    /// Capacity and capabilities of the share holding path
+    ///
+    /// @param path      The smb url of the share, or of any file or
+    ///                  directory on it
+    ///
+    /// @return          The share's statistics, see SmbcFsStats for what
+    ///                  libsmbclient reports.  Error with errno set:
+    ///                  - ENOENT  path does not exist
+    ///                  - EACCES  Permission denied
+    ///                  - EINVAL  path is a workgroup or server url
+    pub fn statvfs(&self, path: &Path) -> SmbcResult<SmbcFsStats> {
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat_buf: statvfs = unsafe { zeroed::<statvfs>() };
+        self.call_retrying("statvfs", path, |ctx| {
+            // libsmbclient does not write to the path
+            check_neg_result(unsafe {
+                (self.statvfs_fn)(ctx, cpath.as_ptr() as *mut c_char, &mut stat_buf)
+            })
+        })?;
+        Ok(SmbcFsStats::from(stat_buf))
     }
 
     ///@ingroup directory
@@ -1567,16 +2174,12 @@ impl Smbc {
     ///                  - EACCES You do not have access to the file
     ///                  - ENOMEM Insufficient kernel memory was available
     pub fn unlink(&self, path: &Path) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "unlink")?;
 
-        check_neg_result(unsafe { (self.unlink_fn)(ptr.0, path.as_ptr()) })?;
+        ptr.call("unlink", path, |ctx| {
+            check_neg_result(unsafe { (self.unlink_fn)(ctx, cpath.as_ptr()) })
+        })?;
 
         Ok(())
     }
@@ -1595,15 +2198,11 @@ impl Smbc {
     ///                  - EINVAL The client library is not properly initialized
     ///                  - EPERM  Permission was denied.
     pub fn utimes(&self, path: &Path, tbuf: &mut Vec<timeval>) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        check_neg_result(unsafe { (self.utimes_fn)(ptr.0, path.as_ptr(), tbuf.as_mut_ptr()) })?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let ptr = lock(&self.context, "utimes")?;
+        ptr.call("utimes", path, |ctx| {
+            check_neg_result(unsafe { (self.utimes_fn)(ctx, cpath.as_ptr(), tbuf.as_mut_ptr()) })
+        })?;
 
         Ok(())
     }
@@ -1673,41 +2272,38 @@ impl Smbc {
     ///     SidType Sid manually, otherwise it will be NONE.  Also, the parser will
     ///     always return a NUMERIC ACE
     pub fn getxattr(&self, path: &Path, attr: &SmbcXAttr) -> SmbcResult<Vec<u8>> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(format!("{}", attr).as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
+        self.call_retrying("getxattr", path, |ctx| {
+            // Set your buffer to capacity len here
+            let len = check_neg_result(unsafe {
+                (self.getxattr_fn)(
+                    ctx,
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    vec![].as_ptr() as *const _,
+                    0,
+                )
+            })? + 1;
+            trace!(target: "smbc", "Sizing buffer to {}", len);
+            let mut value: Vec<u8> = Vec::with_capacity(len as usize);
+            let res = check_neg_result(unsafe {
+                (self.getxattr_fn)(
+                    ctx,
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const _,
+                    len as _,
+                )
+            })?;
+            if i64::from(res) < 0 {
+                trace!(target: "smbc", "getxattr failed");
             }
-        };
-        // Set your buffer to capacity len here
-        let len = check_neg_result(unsafe {
-            (self.getxattr_fn)(ptr.0, path.as_ptr(), name.as_ptr(), vec![].as_ptr() as *const _, 0)
-        })? + 1;
-        trace!(target: "smbc", "Sizing buffer to {}", len);
-        let mut value: Vec<u8> = Vec::with_capacity(len as usize);
-        if i64::from(len) < 0 {
-            trace!(target: "smbc", "getxattr failed");
-        }
-        let res = check_neg_result(unsafe {
-            (self.getxattr_fn)(
-                ptr.0,
-                path.as_ptr(),
-                name.as_ptr(),
-                value.as_ptr() as *const _,
-                len as _,
-            )
-        })?;
-        if i64::from(res) < 0 {
-            trace!(target: "smbc", "getxattr failed");
-        }
-        unsafe {
-            value.set_len(len as usize);
-        }
-
-        Ok(value)
+            unsafe {
+                value.set_len(len as usize);
+            }
+            Ok(value)
+        })
     }
 
     /// While this function is supposed to list only the applicable attributes
@@ -1715,35 +2311,26 @@ impl Smbc {
     /// supported by NT file systems, regardless of whether the referenced
     /// file system supports extended attributes
     pub fn listxattr(&self, path: &Path) -> SmbcResult<Vec<u8>> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        self.call_retrying("listxattr", path, |ctx| {
+            // Set your buffer to capacity len here
+            let temp: Vec<u8> = vec![];
+            let len = check_neg_result(unsafe {
+                (self.listxattr_fn)(ctx, cpath.as_ptr(), temp.as_ptr() as *mut c_char, 0)
+            })?;
+            trace!(target: "smbc", "Sizing buffer to {}", len);
+            let mut value: Vec<u8> = Vec::with_capacity(len as usize);
+            let res = check_neg_result(unsafe {
+                (self.listxattr_fn)(ctx, cpath.as_ptr(), value.as_ptr() as *mut c_char, len as _)
+            })?;
+            if i64::from(res) < 0 {
+                trace!(target: "smbc", "listxattr failed");
             }
-        };
-        // Set your buffer to capacity len here
-        let temp: Vec<u8> = vec![];
-        let len = check_neg_result(unsafe {
-            (self.listxattr_fn)(ptr.0, path.as_ptr(), temp.as_ptr() as *mut c_char, 0)
-        })?;
-        trace!(target: "smbc", "Sizing buffer to {}", len);
-        let mut value: Vec<u8> = Vec::with_capacity(len as usize);
-        if i64::from(len) < 0 {
-            trace!(target: "smbc", "listxattr failed");
-        }
-
-        let res = check_neg_result(unsafe {
-            (self.listxattr_fn)(ptr.0, path.as_ptr(), value.as_ptr() as *mut c_char, len as _)
-        })?;
-        if i64::from(res) < 0 {
-            trace!(target: "smbc", "listxattr failed");
-        }
-        unsafe {
-            value.set_len(len as usize);
-        }
-        Ok(value)
+            unsafe {
+                value.set_len(len as usize);
+            }
+            Ok(value)
+        })
     }
 
     /// NOTE: removexattr only works for the following inputs:
@@ -1764,17 +2351,13 @@ impl Smbc {
     /// See https://ftp.samba.org/pub/pub/unpacked/SOC/2005/SAMBA_3_0/source/libsmb/libsmbclient.c
     /// for details
     pub fn removexattr(&self, path: &Path, attr: &SmbcXAttr) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         let name = CString::new(format!("{}", attr).as_bytes())?;
         //let name = CString::new(name.to_string().as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        check_neg_result(unsafe { (self.removexattr_fn)(ptr.0, path.as_ptr(), name.as_ptr()) })?;
+        let ptr = lock(&self.context, "removexattr")?;
+        ptr.call("removexattr", path, |ctx| {
+            check_neg_result(unsafe { (self.removexattr_fn)(ctx, cpath.as_ptr(), name.as_ptr()) })
+        })?;
 
         Ok(())
     }
@@ -1813,39 +2396,101 @@ impl Smbc {
         value: &SmbcXAttrValue,
         flags: XAttrFlags,
     ) -> SmbcResult<()> {
-        let path = CString::new(path.as_os_str().as_bytes())?;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
         let len = format!("{}", value).len();
         let name = CString::new(format!("{}", attr).as_bytes())?;
         trace!(target: "smbc", "setxattr name {:?}", name);
         let value = CString::new(format!("{}", value).as_bytes())?;
         trace!(target: "smbc", "setxattr value {:?}, len {}", value, len);
         //let name = CString::new(name.to_string().as_bytes())?;
-        let ptr = match self.context.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
+        let ptr = lock(&self.context, "setxattr")?;
+        ptr.call("setxattr", path, |ctx| {
+            check_neg_result(unsafe {
+                (self.setxattr_fn)(
+                    ctx,
+                    cpath.as_ptr(),
+                    name.as_ptr(),
+                    value.as_ptr() as *const _,
+                    len as _,
+                    flags.bits() as _,
+                )
+            })
+        })?;
+        Ok(())
+    }
+}
+
+impl SmbcFile {
+    /// Run a libsmbclient call on the open file (see SmbcHandle::call).
+    /// A read-only file whose connection was lost is opened again first,
+    /// and a failure the retry policy of the context retries loses the
+    /// file, so the next attempt opens it again.
+    fn call_retrying<T>(
+        &self,
+        op: &'static str,
+        mut f: impl FnMut(*mut SMBCCTX, *mut SMBCFILE) -> IoResult<T>,
+    ) -> SmbcResult<T> {
+        let policy = match self.reopen_flags {
+            Some(_) => lock(&self.handle.smbc, op)?.retry_policy(),
+            None => None,
         };
-        let res = unsafe {
-            (self.setxattr_fn)(
-                ptr.0,
-                path.as_ptr(),
-                name.as_ptr(),
-                value.as_ptr() as *const _,
-                len as _,
-                flags.bits() as _,
-            )
+        retry(
+            policy.as_ref(),
+            || self.lose(&*lock(&self.handle.smbc, op)?),
+            || {
+                let ptr = lock(&self.handle.smbc, op)?;
+                self.reopen(&ptr)?;
+                self.handle.call(&ptr, op, &mut f)
+            },
+        )
+    }
+
+    /// Close the handle of a read-only file whose connection was lost,
+    /// keeping its offset to open it again at.  ptr is the locked context.
+    fn lose(&self, ptr: &SmbcPtr) -> SmbcResult<()> {
+        let lost = self.handle.handle.load(Ordering::SeqCst);
+        if self.reopen_flags.is_none() || lost.is_null() {
+            return Ok(());
+        }
+        // libsmbclient keeps the offset itself, SEEK_CUR needs no server
+        let at = self.handle.call(ptr, "lseek", |ctx, handle| {
+            check_neg_result(unsafe { (self.lseek_fn)(ctx, handle, 0, SEEK_CUR as c_int) })
+        })?;
+        *self.handle.reopen_at.lock().unwrap_or_else(PoisonError::into_inner) = Some(at);
+        self.handle.handle.store(ptr::null_mut(), Ordering::SeqCst);
+        unsafe { (self.handle.close_fn)(ptr.0, lost) };
+        // the lost connection stays cached until nothing is open on it
+        ptr.purge_cached_servers()
+    }
+
+    /// Open a lost read-only file again, and seek back to its offset.
+    /// Does nothing unless the file was lost; when opening it fails it
+    /// stays lost, and the next call on it tries again.  ptr is the locked
+    /// context.
+    fn reopen(&self, ptr: &SmbcPtr) -> SmbcResult<()> {
+        let mut reopen_at = self.handle.reopen_at.lock().unwrap_or_else(PoisonError::into_inner);
+        let (at, flags) = match (*reopen_at, self.reopen_flags) {
+            (Some(at), Some(flags)) => (at, flags),
+            _ => return Ok(()),
         };
-        if i64::from(res) < 0 {
-            trace!(target: "smbc", "setxattr failed");
-            check_neg_result(res)?;
+        trace!(target: "smbc", "reopening {:?} at {}", self.handle.path, at);
+        let path = &self.handle.path;
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let handle = ptr.call("open", path, |ctx| {
+            check_mut_ptr(unsafe { (self.open_fn)(ctx, cpath.as_ptr(), flags.bits(), 0 as mode_t) })
+        })?;
+        let seek = ptr.call("lseek", path, |ctx| {
+            check_neg_result(unsafe { (self.lseek_fn)(ctx, handle, at, SEEK_SET as c_int) })
+        });
+        if let Err(e) = seek {
+            unsafe { (self.handle.close_fn)(ptr.0, handle) };
+            return Err(e);
         }
+        self.handle.handle.store(handle, Ordering::SeqCst);
+        *reopen_at = None;
         Ok(())
     }
-}
 
-impl SmbcFile {
     /// Read from a file using an opened file handle.
     /// @param count   Size of buf in bytes
     ///
@@ -1865,15 +2510,10 @@ impl SmbcFile {
     /// calling read again will give you an empty vec
     pub fn fread(&self, count: u64) -> SmbcResult<Vec<u8>> {
         let mut buf: Vec<u8> = Vec::with_capacity(count as usize);
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let bytes_read = check_neg_result(unsafe {
-            (self.read_fn)(ptr.0, self.handle, buf.as_mut_ptr() as *mut _, count as usize)
+        let bytes_read = self.call_retrying("read", |ctx, handle| {
+            check_neg_result(unsafe {
+                (self.read_fn)(ctx, handle, buf.as_mut_ptr() as *mut _, count as usize)
+            })
         })?;
         if (bytes_read as i64) < 0 {
             trace!(target: "smbc", "read failed");
@@ -1896,15 +2536,11 @@ impl SmbcFile {
     ///
     /// Please NOTE that fwrite writes from the current file offset
     pub fn fwrite(&self, buf: &[u8]) -> SmbcResult<isize> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let bytes_wrote = check_neg_result(unsafe {
-            (self.write_fn)(ptr.0, self.handle, buf.as_ptr() as *const _, buf.len() as _)
+        let ptr = lock(&self.handle.smbc, "write")?;
+        let bytes_wrote = self.handle.call(&ptr, "write", |ctx, handle| {
+            check_neg_result(unsafe {
+                (self.write_fn)(ctx, handle, buf.as_ptr() as *const _, buf.len() as _)
+            })
         })?;
         if (bytes_wrote as i64) < 0 {
             trace!(target: "smbc", "write failed");
@@ -1931,38 +2567,69 @@ impl SmbcFile {
     ///                  - EBADF  Fildes is not an open file descriptor.
     ///                  - EINVAL Whence is not a proper value or smbc_init
     ///     		     not called.
+    ///
+    /// @note: a read-only file whose connection was lost is not opened
+    ///        again by SEEK_SET or SEEK_CUR, they move the offset it will
+    ///        be opened again at
     pub fn lseek(&self, offset: i64, whence: i32) -> SmbcResult<off_t> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let res = is_einval(unsafe { (self.lseek_fn)(ptr.0, self.handle, offset, whence) })?;
+        if let Some(at) = self.seek_lost(offset, whence)? {
+            return Ok(at);
+        }
+        let res = self.call_retrying("lseek", |ctx, handle| {
+            is_einval(unsafe { (self.lseek_fn)(ctx, handle, offset, whence) })
+        })?;
         Ok(res as off_t)
     }
 
+    /// Seek a lost read-only file by moving the offset it is opened again
+    /// at.  None when the file is not lost, or the seek needs its size.
+    fn seek_lost(&self, offset: off_t, whence: c_int) -> SmbcResult<Option<off_t>> {
+        let _ptr = lock(&self.handle.smbc, "lseek")?;
+        let mut reopen_at = self.handle.reopen_at.lock().unwrap_or_else(PoisonError::into_inner);
+        let at = match (*reopen_at, whence as u32) {
+            (Some(_), SEEK_SET) => Some(offset),
+            (Some(at), SEEK_CUR) => at.checked_add(offset),
+            _ => return Ok(None),
+        };
+        match at.filter(|&at| at >= 0) {
+            Some(at) => {
+                *reopen_at = Some(at);
+                Ok(Some(at))
+            }
+            None => {
+                let err = Error::from_raw_os_error(EINVAL);
+                Err(SmbcError::from_errno("lseek", &self.handle.path, err))
+            }
+        }
+    }
+
     /// fstat
     /// NOTE: stat notes apply
     /// Please note that fstat called on a directory entry will not work
     /// fstatdir is NOT implemented in the SMB Client library:
     /// See https://ftp.samba.org/pub/pub/unpacked/SOC/2005/SAMBA_3_0/source/libsmb/libsmbclient.c for details.
     /// Please use stat for directory meta attributes
-    pub fn fstat(&self) -> SmbcResult<stat> {
+    pub fn fstat(&self) -> SmbcResult<SmbcMetadata> {
         let mut stat_buf: stat = unsafe { zeroed::<stat>() };
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let res = check_neg_result(unsafe { (self.fstat_fn)(ptr.0, self.handle, &mut stat_buf) })?;
+        let res = self.call_retrying("fstat", |ctx, handle| {
+            check_neg_result(unsafe { (self.fstat_fn)(ctx, handle, &mut stat_buf) })
+        })?;
         if i64::from(res) < 0 {
             trace!(target: "smbc", "fstat failed");
         }
-        Ok(stat_buf)
+        Ok(SmbcMetadata::from(stat_buf))
+    }
+
+    /// fstatvfs
+    /// Capacity and capabilities of the share the file is on
+    /// NOTE: statvfs notes apply
+    pub fn fstatvfs(&self) -> SmbcResult<SmbcFsStats> {
+        let mut stat_buf: statvfs = unsafe { zeroed::<statvfs>() };
+        let ptr = lock(&self.handle.smbc, "fstatvfs")?;
+        self.handle.call(&ptr, "fstatvfs", |ctx, handle| {
+            check_neg_result(unsafe { (self.fstatvfs_fn)(ctx, handle, &mut stat_buf) })
+        })?;
+        Ok(SmbcFsStats::from(stat_buf))
     }
 
     /// ftruncate
@@ -1978,33 +2645,116 @@ impl SmbcFile {
     /// 		           or smbc_init not called.
     ///                  - ENOMEM Out of memory
     pub fn ftruncate(&self, size: i64) -> SmbcResult<()> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        check_neg_result(unsafe { (self.ftruncate_fn)(ptr.0, self.handle, size as off_t) })?;
+        let ptr = lock(&self.handle.smbc, "ftruncate")?;
+        self.handle.call(&ptr, "ftruncate", |ctx, handle| {
+            check_neg_result(unsafe { (self.ftruncate_fn)(ctx, handle, size as off_t) })
+        })?;
         Ok(())
     }
+
+    /// Read from the file at offset, like std::os::unix::fs::FileExt.
+    /// The seek and the read happen under one lock of the context, so
+    /// threads sharing the file cannot move its offset in between, and
+    /// the file offset is left where it was.
+    ///
+    /// @param buf       buffer to read into
+    ///
+    /// @param offset    offset from the start of the file
+    ///
+    /// @return          the number of bytes read, 0 at end of file.
+    ///                  Errors are as for fread and lseek.
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> SmbcResult<usize> {
+        self.call_retrying("read", |ctx, handle| {
+            self.at_offset(ctx, handle, offset, || {
+                check_neg_result(unsafe {
+                    (self.read_fn)(ctx, handle, buf.as_mut_ptr() as *mut _, buf.len())
+                })
+            })
+        })
+    }
+
+    /// Write to the file at offset, like std::os::unix::fs::FileExt.
+    /// The seek and the write happen under one lock of the context, so
+    /// threads sharing the file cannot move its offset in between, and
+    /// the file offset is left where it was.
+    ///
+    /// @param buf       bytes to write
+    ///
+    /// @param offset    offset from the start of the file
+    ///
+    /// @return          the number of bytes written.
+    ///                  Errors are as for fwrite and lseek.
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> SmbcResult<usize> {
+        let ptr = lock(&self.handle.smbc, "write")?;
+        self.handle.call(&ptr, "write", |ctx, handle| {
+            self.at_offset(ctx, handle, offset, || {
+                check_neg_result(unsafe {
+                    (self.write_fn)(ctx, handle, buf.as_ptr() as *const _, buf.len())
+                })
+            })
+        })
+    }
+
+    /// Run io at offset and seek back to the current offset afterwards.
+    /// The context must be locked.
+    fn at_offset(
+        &self,
+        ctx: *mut SMBCCTX,
+        handle: *mut SMBCFILE,
+        offset: u64,
+        io: impl FnOnce() -> IoResult<isize>,
+    ) -> IoResult<usize> {
+        let seek = |off: off_t, whence: u32| {
+            is_einval(unsafe { (self.lseek_fn)(ctx, handle, off, whence as c_int) })
+        };
+        let saved = seek(0, SEEK_CUR)?;
+        seek(offset as off_t, SEEK_SET)?;
+        let res = io();
+        let restored = seek(saved, SEEK_SET);
+        let n = res?;
+        restored?;
+        Ok(n as usize)
+    }
+
+    /// Close the file.  Unlike dropping it, this reports the errors the
+    /// server only gives at close, such as a write that failed after
+    /// fwrite returned.
+    ///
+    /// @note: Clones of this file share its handle, they fail with EBADF
+    ///        once it is closed.
+    pub fn close(self) -> SmbcResult<()> {
+        let ptr = lock(&self.handle.smbc, "close")?;
+        self.handle.close(&ptr)
+    }
+}
+
+/// The io::Error of a failed SmbcFile call, by its errno when it has one
+fn io_error(e: SmbcError) -> Error {
+    match e {
+        SmbcError::IoError(e) => e,
+        e => match e.raw_os_error() {
+            Some(errno) => Error::from_raw_os_error(errno),
+            None => Error::other(e),
+        },
+    }
 }
 
 /// Read trait for SmbcFile
 /// pretty much does the same thing as fread above
+/// A read-only file whose connection is lost is opened again at the same
+/// offset when the retry policy of its context retries the failure, or by
+/// the next read, seek or fstat when the retries run out first.
 impl Read for SmbcFile {
     fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
         trace!(target: "smbc", "reading file to buf");
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        Ok(check_neg_result(unsafe {
-            (self.read_fn)(ptr.0, self.handle, buf.as_mut_ptr() as *mut _, buf.len() as _)
-        })? as usize)
+        let n = self
+            .call_retrying("read", |ctx, handle| {
+                check_neg_result(unsafe {
+                    (self.read_fn)(ctx, handle, buf.as_mut_ptr() as *mut _, buf.len() as _)
+                })
+            })
+            .map_err(io_error)?;
+        Ok(n as usize)
     }
 }
 
@@ -2013,15 +2763,10 @@ impl Read for SmbcFile {
 impl Write for SmbcFile {
     fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
         trace!(target: "smbc", "writing buf to file");
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
+        let ptr = lock(&self.handle.smbc, "write").map_err(Error::other)?;
+        let handle = self.handle.get()?;
         Ok(check_neg_result(unsafe {
-            (self.write_fn)(ptr.0, self.handle, buf.as_ptr() as *const _, buf.len() as _)
+            (self.write_fn)(ptr.0, handle, buf.as_ptr() as *const _, buf.len() as _)
         })? as usize)
     }
 
@@ -2036,19 +2781,12 @@ impl Write for SmbcFile {
 impl Seek for SmbcFile {
     fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
         trace!(target: "smbc", "seek file to {:?}", pos);
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
         let (whence, off) = match pos {
             SeekFrom::Start(p) => (SEEK_SET, p as off_t),
             SeekFrom::End(p) => (SEEK_END, p as off_t),
             SeekFrom::Current(p) => (SEEK_CUR, p as off_t),
         };
-        let ret = is_einval(unsafe { (self.lseek_fn)(ptr.0, self.handle, off, whence as i32) })?;
+        let ret = self.lseek(off, whence as i32).map_err(io_error)?;
         Ok(ret as u64)
     }
 }
@@ -2064,35 +2802,38 @@ impl SmbcDirectory {
 
     /// readdir
     /// Get a single directory entry.
-    /// @return          SmbcDirEntry of next directory else
-    ///                  error occurs or end-of-directory is reached:
+    /// @return          SmbcDirEntry of next directory, None at the end
+    ///                  of the directory.  Error:
     ///                  - EBADF Invalid directory handle
     ///                  - EINVAL smbc_init() failed or has not been called
-    pub fn readdir(&self) -> IoResult<SmbcDirEntry> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
+    ///                  Parse if the entry has an unknown type
+    ///
+    /// @note: names are percent-decoded when the context has
+    ///        url_encode_readdir_entries on, so they are the same either way
+    pub fn readdir(&self) -> SmbcResult<Option<SmbcDirEntry>> {
+        let ptr = lock(&self.handle.smbc, "readdir")?;
+        let dirent = self.handle.call(&ptr, "readdir", |ctx, handle| {
+            // the end of the directory is a null without an errno
+            Errno::clear();
+            let dirent = unsafe { (self.readdir_fn)(ctx, handle) };
+            if dirent.is_null() && Errno::last() != Errno::UnknownErrno {
+                return Err(Error::last_os_error());
             }
-        };
-        let dirent = check_mut_ptr(unsafe { (self.readdir_fn)(ptr.0, self.handle) })?;
-        trace!(target: "smbc", "readdir function successful!");
+            Ok(dirent)
+        })?;
         if dirent.is_null() {
-            let e = Error::new(ErrorKind::Other, "dirent null");
-            return Err(e);
+            trace!(target: "smbc", "end of directory {:?}", self.handle.path);
+            return Ok(None);
         }
-        let ptr = unsafe { (&(*dirent).name) as *const i8 };
-        let filename = (unsafe { CStr::from_ptr(ptr) }).to_string_lossy();
-        trace!(target: "smbc", "Filename: {:?}", filename);
-        let d_type = match SmbcType::from(unsafe { (*dirent).smbc_type }) {
-            Ok(ty) => ty,
-            Err(e) => {
-                return Err(e);
-            }
-        };
+        let name = unsafe { CStr::from_ptr((*dirent).name.as_ptr()) };
+        let path = entry_name(name.to_bytes(), url_encoded(&ptr));
+        trace!(target: "smbc", "Filename: {:?}", path);
+        let s_type = SmbcType::from(unsafe { (*dirent).smbc_type })
+            .map_err(|e| SmbcError::Parse(SmbcOpError::new("readdir", &self.handle.path, e)))?;
         let comment = unsafe { CStr::from_ptr((*dirent).comment).to_string_lossy().into_owned() };
-        Ok(SmbcDirEntry { s_type: d_type, comment, path: PathBuf::from(filename.into_owned()) })
+        let entry = SmbcDirEntry { s_type, comment, path };
+        self.advance(std::slice::from_ref(&entry));
+        Ok(Some(entry))
     }
 
     /// lseek on directories.
@@ -2110,20 +2851,56 @@ impl SmbcDirectory {
     ///                  - EINVAL offset did not refer to a valid dirent or
     ///             	   smbc_init not called.
     pub fn lseekdir(&self, offset: i64) -> SmbcResult<()> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let res = is_einval(unsafe { (self.lseekdir_fn)(ptr.0, self.handle, offset as off_t) })?;
+        let ptr = lock(&self.handle.smbc, "lseekdir")?;
+        let res = self.handle.call(&ptr, "lseekdir", |ctx, handle| {
+            is_einval(unsafe { (self.lseekdir_fn)(ctx, handle, offset as off_t) })
+        })?;
         if i64::from(res) < 0 {
             trace!(target: "smbc", "lseekdir failed");
         }
+        let start = SmbcDirPosition { count: Some(0), last: None, failed: false };
+        self.set_position(if offset == 0 { start } else { SmbcDirPosition::default() });
         Ok(())
     }
 
+    /// getdents
+    /// Read as many directory entries as fit in max bytes with one call,
+    /// instead of one readdir call per entry.  An entry takes
+    /// size_of::<smbc_dirent>() bytes plus the length of its name and
+    /// comment.
+    ///
+    /// @param max       The buffer size in bytes (at most i32::MAX)
+    ///
+    /// @return          The entries read, empty at the end of the
+    ///                  directory.  Error:
+    ///                  - EINVAL the next entry does not fit in max bytes
+    ///                  - EBADF Invalid directory handle
+    ///                  - ENOENT the directory was removed
+    ///                  Parse if an entry cannot be decoded
+    ///
+    /// @note: getdents shares its place in the directory with readdir.
+    pub fn read_batch(&self, max: usize) -> SmbcResult<Vec<SmbcDirEntry>> {
+        let max = max.min(c_int::MAX as usize);
+        // u64s so that the records start aligned
+        let mut buf = vec![0u64; max.div_ceil(size_of::<u64>())];
+        let ptr = lock(&self.handle.smbc, "getdents")?;
+        let len = self.handle.call(&ptr, "getdents", |ctx, handle| {
+            let dirp = buf.as_mut_ptr() as *mut smbc_dirent;
+            check_neg_result(unsafe { (self.getdents_fn)(ctx, handle, dirp, max as c_int) })
+        })?;
+        let bytes = unsafe { std::slice::from_raw_parts(buf.as_ptr() as *const u8, max) };
+        let entries = decode_dirents(&bytes[..(len as usize).min(max)], url_encoded(&ptr))
+            .map_err(|e| SmbcError::Parse(SmbcOpError::new("getdents", &self.handle.path, e)))?;
+        self.advance(&entries);
+        Ok(entries)
+    }
+
+    /// An iterator of the entries, read with read_batch(max) (max grows
+    /// when an entry does not fit)
+    pub fn read_batch_iter(&self, max: usize) -> SmbcReadBatch<'_> {
+        SmbcReadBatch { dir: self, max, pending: VecDeque::new() }
+    }
+
     /// Get the current directory offset.
     /// smbc_telldir() may be used in conjunction with smbc_readdir() and
     /// smbc_lseekdir().
@@ -2136,15 +2913,256 @@ impl SmbcDirectory {
     ///                 - EINVAL smbc_init() failed or has not been called
     ///                 - ENOTDIR if dh is not a directory
     pub fn telldir(&self) -> SmbcResult<off_t> {
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
+        let ptr = lock(&self.handle.smbc, "telldir")?;
+        let res = self.handle.call(&ptr, "telldir", |ctx, handle| {
+            is_einval(unsafe { (self.telldir_fn)(ctx, handle) })
+        })?;
+        Ok(res as off_t)
+    }
+
+    /// readdirplus
+    /// Get a single directory entry along with its metadata, from the
+    /// same directory listing the server sends readdir (so without a stat
+    /// call per entry).
+    ///
+    /// @return          The next entry, None at the end of the directory.
+    ///                  Error:
+    ///                  - EBADF Invalid directory handle
+    ///                  - EINVAL smbc_init() failed or has not been called
+    ///                  - ENOMEM Out of memory
+    ///
+    /// @note: readdirplus keeps its own place in the directory, apart
+    ///        from readdir's.  Like readdir it returns "." and "..".
+    pub fn readdirplus(&self) -> SmbcResult<Option<SmbcDirPlusEntry>> {
+        let mut stat_buf: stat = unsafe { zeroed::<stat>() };
+        let ptr = lock(&self.handle.smbc, "readdirplus")?;
+        let info = self.handle.call(&ptr, "readdirplus", |ctx, handle| {
+            // the end of the directory is a null without an errno
+            Errno::clear();
+            let info = unsafe { (self.readdirplus2_fn)(ctx, handle, &mut stat_buf) };
+            if info.is_null() && Errno::last() != Errno::UnknownErrno {
+                return Err(Error::last_os_error());
             }
+            Ok(info)
+        })?;
+        if info.is_null() {
+            return Ok(None);
+        }
+        let info = unsafe { &*info };
+        let name = unsafe { CStr::from_ptr(info.name) };
+        let name = PathBuf::from(OsStr::from_bytes(name.to_bytes()));
+        let short_name = if info.short_name.is_null() {
+            String::new()
+        } else {
+            unsafe { CStr::from_ptr(info.short_name).to_string_lossy().into_owned() }
         };
-        let res = is_einval(unsafe { (self.telldir_fn)(ptr.0, self.handle) })?;
-        Ok(res as off_t)
+        trace!(target: "smbc", "readdirplus: {:?}", name);
+        let dos_mode = DosMode::from_bits_truncate(i32::from(info.attrs));
+        let metadata = SmbcMetadata::new(stat_buf, Some(info.btime_ts), Some(dos_mode));
+        Ok(Some(SmbcDirPlusEntry { path: name, short_name, metadata }))
+    }
+
+    /// An iterator of readdirplus entries
+    pub fn readdirplus_iter(&self) -> SmbcReaddirPlus<'_> {
+        SmbcReaddirPlus { dir: self }
+    }
+
+    /// The smb url of an entry of this directory
+    pub fn entry_url(&self, name: &Path) -> PathBuf {
+        entry_url(&self.handle.path, name)
+    }
+
+    /// The place readdir and getdents have read to, to carry on from
+    /// with seek_cursor
+    pub fn cursor(&self) -> SmbcResult<SmbcDirCursor> {
+        let ptr = lock(&self.handle.smbc, "telldir")?;
+        let offset = self.handle.call(&ptr, "telldir", |ctx, handle| {
+            // telldir at the end of the directory is -1 without an errno
+            Errno::clear();
+            match unsafe { (self.telldir_fn)(ctx, handle) } {
+                -1 if Errno::last() == Errno::UnknownErrno => Ok(None),
+                -1 => Err(Error::last_os_error()),
+                offset => Ok(Some(offset)),
+            }
+        })?;
+        let read = self.position();
+        Ok(SmbcDirCursor { dir: self.id, offset, count: read.count, last: read.last })
+    }
+
+    /// Carry on reading from cursor.
+    ///
+    /// A cursor of this directory (or a clone of it) is seeked to with
+    /// lseekdir.  In any other directory, for example the same directory
+    /// opened again after a reconnect, the listing is read from the start
+    /// up to the entry named last_name.  When that entry is gone the first
+    /// position entries are skipped instead.
+    ///
+    /// @return          Nothing on success.  Error:
+    ///                  - NotFound the cursor has no position and its
+    ///                  entry is gone
+    ///                  - EINVAL the cursor is from an lseekdir to an
+    ///                  offset of another directory
+    ///                  Otherwise errors are as for lseekdir and readdir.
+    pub fn seek_cursor(&self, cursor: &SmbcDirCursor) -> SmbcResult<()> {
+        if cursor.dir == self.id {
+            match cursor.offset {
+                Some(offset) => self.lseekdir(offset)?,
+                None => while self.readdir()?.is_some() {},
+            }
+            let last = cursor.last.clone();
+            self.set_position(SmbcDirPosition { count: cursor.count, last, failed: false });
+            return Ok(());
+        }
+        self.lseekdir(0)?;
+        if let Some(last) = &cursor.last {
+            while let Some(entry) = self.readdir()? {
+                if entry.path == *last {
+                    return Ok(());
+                }
+            }
+            self.lseekdir(0)?;
+        }
+        let errno = match (cursor.count, &cursor.last) {
+            (Some(count), _) => {
+                for _ in 0..count {
+                    if self.readdir()?.is_none() {
+                        break;
+                    }
+                }
+                return Ok(());
+            }
+            (None, Some(_)) => ENOENT,
+            (None, None) => EINVAL,
+        };
+        let err = Error::from_raw_os_error(errno);
+        Err(SmbcError::from_errno("seekdir", &self.handle.path, err))
+    }
+
+    fn position(&self) -> SmbcDirPosition {
+        self.read.lock().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    fn set_position(&self, position: SmbcDirPosition) {
+        *self.read.lock().unwrap_or_else(PoisonError::into_inner) = position;
+    }
+
+    /// Count entries as read
+    fn advance(&self, entries: &[SmbcDirEntry]) {
+        let mut read = self.read.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(entry) = entries.last() {
+            read.count = read.count.map(|c| c + entries.len() as u64);
+            read.last = Some(entry.path.clone());
+        }
+    }
+
+    /// Metadata of an entry read from this directory (one stat call on
+    /// the server per entry, see readdirplus to avoid them)
+    pub fn metadata(&self, entry: &SmbcDirEntry) -> SmbcResult<SmbcMetadata> {
+        let path = self.entry_url(&entry.path);
+        let cpath = CString::new(path.as_os_str().as_bytes())?;
+        let mut stat_buf: stat = unsafe { zeroed::<stat>() };
+        let ptr = lock(&self.handle.smbc, "stat")?;
+        ptr.call("stat", &path, |ctx| {
+            check_neg_result(unsafe { (self.stat_fn)(ctx, cpath.as_ptr(), &mut stat_buf) })
+        })?;
+        Ok(SmbcMetadata::from(stat_buf))
+    }
+
+    /// Close the directory, reporting any error closedir gives.
+    ///
+    /// @note: Clones of this directory share its handle, they fail with
+    ///        EBADF once it is closed.
+    pub fn close(self) -> SmbcResult<()> {
+        let ptr = lock(&self.handle.smbc, "closedir")?;
+        self.handle.close(&ptr)
+    }
+}
+
+/// Decode the smbc_dirent records getdents wrote.  Each record is dirlen
+/// bytes long: the header, then the name and the comment (each followed
+/// by a nul) in place of the name field.
+fn decode_dirents(buf: &[u8], url_encoded: bool) -> IoResult<Vec<SmbcDirEntry>> {
+    let header = offset_of!(smbc_dirent, name);
+    let invalid = |msg| Error::new(ErrorKind::InvalidData, msg);
+    fn until_nul(b: &[u8]) -> &[u8] {
+        b.iter().position(|&c| c == 0).map_or(b, |end| &b[..end])
+    }
+    let mut entries = vec![];
+    let mut rest = buf;
+    while !rest.is_empty() {
+        if rest.len() < header {
+            return Err(invalid("truncated smbc_dirent header"));
+        }
+        let field = |off: usize| {
+            let mut bytes = [0u8; size_of::<c_uint>()];
+            bytes.copy_from_slice(&rest[off..off + size_of::<c_uint>()]);
+            c_uint::from_ne_bytes(bytes) as usize
+        };
+        let dirlen = field(offset_of!(smbc_dirent, dirlen));
+        let namelen = field(offset_of!(smbc_dirent, namelen));
+        let commentlen = field(offset_of!(smbc_dirent, commentlen));
+        if dirlen < header || dirlen > rest.len() || header + namelen + 1 + commentlen > dirlen {
+            return Err(invalid("smbc_dirent lengths overrun the record"));
+        }
+        let record = &rest[..dirlen];
+        let name = entry_name(until_nul(&record[header..header + namelen]), url_encoded);
+        let comment = &record[header + namelen + 1..header + namelen + 1 + commentlen];
+        let comment = String::from_utf8_lossy(until_nul(comment)).into_owned();
+        let s_type = SmbcType::from(field(offset_of!(smbc_dirent, smbc_type)) as c_uint)?;
+        entries.push(SmbcDirEntry { s_type, comment, path: name });
+        rest = &rest[dirlen..];
+    }
+    Ok(entries)
+}
+
+/// The name of a readdir or getdents entry, percent-decoded when the
+/// context has url_encode_readdir_entries on
+fn entry_name(name: &[u8], url_encoded: bool) -> PathBuf {
+    if url_encoded {
+        PathBuf::from(OsString::from_vec(percent_decode(name).collect()))
+    } else {
+        PathBuf::from(OsString::from_vec(name.to_vec()))
+    }
+}
+
+/// The smb url of the entry name of the directory at url dir.  The name is
+/// percent-encoded byte for byte, so names that are not UTF-8 survive.
+fn entry_url(dir: &Path, name: &Path) -> PathBuf {
+    let dir = dir.as_os_str().as_bytes();
+    let end = dir.iter().rposition(|&c| c != b'/').map_or(0, |i| i + 1);
+    let mut url = dir[..end].to_vec();
+    url.push(b'/');
+    url.extend(percent_encode(name.as_os_str().as_bytes(), URL_NAME).flat_map(str::bytes));
+    PathBuf::from(OsString::from_vec(url))
+}
+
+/// The largest buffer SmbcReadBatch grows to when an entry does not fit
+const MAX_BATCH_BYTES: usize = 1 << 20;
+
+/// An iterator of the entries of an SmbcDirectory, read in batches with
+/// read_batch
+pub struct SmbcReadBatch<'a> {
+    dir: &'a SmbcDirectory,
+    max: usize,
+    pending: VecDeque<SmbcDirEntry>,
+}
+
+impl<'a> Iterator for SmbcReadBatch<'a> {
+    type Item = SmbcResult<SmbcDirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.pending.is_empty() {
+            match self.dir.read_batch(self.max) {
+                Ok(batch) if batch.is_empty() => return None,
+                Ok(batch) => self.pending.extend(batch),
+                // the next entry does not fit, make room for it
+                Err(e) if e.raw_os_error() == Some(EINVAL) && self.max < MAX_BATCH_BYTES => {
+                    self.max = (self.max * 2).clamp(size_of::<smbc_dirent>(), MAX_BATCH_BYTES);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        self.pending.pop_front().map(Ok)
     }
 }
 
@@ -2152,48 +3170,34 @@ impl SmbcDirectory {
 /// When you use opendir to open a directory, you can use this iterator
 /// to loop through all files/subdirectories
 /// (Or you can just call readdir over an over)
+/// A failed readdir is an Err, not the end of the directory.  Like
+/// std::fs::ReadDir the iterator ends after the error; carry on reading
+/// with seek_cursor (or lseekdir) to a cursor taken before it.
 impl Iterator for SmbcDirectory {
-    type Item = IoResult<SmbcDirEntry>;
+    type Item = SmbcResult<SmbcDirEntry>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        trace!(target: "smbc", "Attempting to retrieve readdir function");
-        trace!(target: "smbc", "Handle: {:?}", self.handle);
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let dirent = match check_mut_ptr(unsafe { (self.readdir_fn)(ptr.0, self.handle) }) {
-            Ok(d) => d,
-            Err(e) => {
-                trace!(target: "smbc", "Error! {:?}", e);
-                return None;
-            }
-        };
-        trace!(target: "smbc", "Readdir successful!");
-        if dirent.is_null() {
-            trace!(target: "smbc", "Directory is NULL!!! T");
-            // Null means we're done
+        if self.read.lock().unwrap_or_else(PoisonError::into_inner).failed {
             return None;
         }
-        let ptr = unsafe { (&(*dirent).name) as *const i8 };
-        let name = (unsafe { CStr::from_ptr(ptr) }).to_string_lossy().into_owned();
+        let entry = self.readdir().transpose();
+        if let Some(Err(_)) = entry {
+            self.read.lock().unwrap_or_else(PoisonError::into_inner).failed = true;
+        }
+        entry
+    }
+}
 
-        let filename = percent_decode(name.as_bytes()).decode_utf8_lossy();
-        trace!(target: "smbc", "Filename: {:?}", filename);
+/// An iterator of the readdirplus entries of an SmbcDirectory
+pub struct SmbcReaddirPlus<'a> {
+    dir: &'a SmbcDirectory,
+}
 
-        let s_type = match unsafe { SmbcType::from((*dirent).smbc_type) } {
-            Ok(ty) => ty,
-            Err(e) => {
-                return Some(Err(e));
-            }
-        };
-        trace!(target: "smbc", "FileType: {:?}", s_type);
-        let comment = unsafe { CStr::from_ptr((*dirent).comment).to_string_lossy().into_owned() };
-        trace!(target: "smbc", "Comment: {:?}", comment);
-        Some(Ok(SmbcDirEntry { s_type, comment, path: PathBuf::from(filename.into_owned()) }))
+impl<'a> Iterator for SmbcReaddirPlus<'a> {
+    type Item = SmbcResult<SmbcDirPlusEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.dir.readdirplus().transpose()
     }
 }
 
@@ -2203,20 +3207,13 @@ impl Iterator for SmbcDirectory {
 impl Seek for SmbcDirectory {
     fn seek(&mut self, pos: SeekFrom) -> IoResult<u64> {
         trace!(target: "smbc", "seeking file {:?}", pos);
-        let ptr = match self.smbc.lock() {
-            Ok(p) => p,
-            Err(e) => {
-                error!("Poisoned mutex {:?}", e);
-                panic!("POISONED MUTEX {:?}!!!!", e)
-            }
-        };
-        let (_, off) = match pos {
-            SeekFrom::Start(p) => (SEEK_SET, p as off_t),
-            SeekFrom::End(p) => (SEEK_END, p as off_t),
-            SeekFrom::Current(p) => (SEEK_CUR, p as off_t),
+        let off = match pos {
+            SeekFrom::Start(p) => p as off_t,
+            SeekFrom::End(p) => p as off_t,
+            SeekFrom::Current(p) => p as off_t,
         };
-        let res = is_einval(unsafe { (self.lseekdir_fn)(ptr.0, self.handle, off as off_t) })?;
-        Ok(res as u64)
+        self.lseekdir(off).map_err(Error::other)?;
+        Ok(0)
     }
 }
 